@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS anime_list (
+    title TEXT PRIMARY KEY,
+    archived BOOLEAN NOT NULL DEFAULT false,
+    animes INT[] NOT NULL DEFAULT '{}'
+);
+
+CREATE TABLE IF NOT EXISTS anime_state (
+    anime_id INT PRIMARY KEY,
+    anime_item JSONB NOT NULL,
+    favorite BOOLEAN NOT NULL DEFAULT false,
+    watched_episodes JSONB NOT NULL DEFAULT '[]',
+    visible BOOLEAN NOT NULL DEFAULT true,
+    rating INT
+);
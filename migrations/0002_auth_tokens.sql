@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS auth_tokens (
+    token TEXT PRIMARY KEY,
+    issued_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1 @@
+ALTER TABLE anime_state ALTER COLUMN rating TYPE REAL;
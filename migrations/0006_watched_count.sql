@@ -0,0 +1,9 @@
+-- watched_episodes is either a legacy array of episode numbers or a map of
+-- episode number to watch time
+CREATE OR REPLACE FUNCTION watched_count(episodes JSONB) RETURNS INT AS $$
+    SELECT CASE jsonb_typeof(episodes)
+        WHEN 'array' THEN jsonb_array_length(episodes)
+        WHEN 'object' THEN (SELECT COUNT(*)::int FROM jsonb_object_keys(episodes))
+        ELSE 0
+    END
+$$ LANGUAGE SQL IMMUTABLE;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS share_tokens (
+    token TEXT PRIMARY KEY,
+    watch_list_name TEXT NOT NULL REFERENCES anime_list (title)
+        ON UPDATE CASCADE ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
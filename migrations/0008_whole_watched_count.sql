@@ -0,0 +1,15 @@
+-- like watched_count, but ignores .5 specials and recaps so the result can be
+-- compared against total_episodes
+CREATE OR REPLACE FUNCTION whole_watched_count(episodes JSONB) RETURNS INT AS $$
+    SELECT CASE jsonb_typeof(episodes)
+        WHEN 'array' THEN (
+            SELECT COUNT(*)::int FROM jsonb_array_elements(episodes) AS ep
+            WHERE ep::numeric = floor(ep::numeric)
+        )
+        WHEN 'object' THEN (
+            SELECT COUNT(*)::int FROM jsonb_object_keys(episodes) AS ep
+            WHERE ep NOT LIKE '%.%'
+        )
+        ELSE 0
+    END
+$$ LANGUAGE SQL IMMUTABLE;
@@ -0,0 +1,2 @@
+-- position in the user's own ranking, 1 being the favourite; unranked is NULL
+ALTER TABLE anime_state ADD COLUMN IF NOT EXISTS personal_rank INT;
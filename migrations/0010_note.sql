@@ -0,0 +1 @@
+ALTER TABLE anime_state ADD COLUMN IF NOT EXISTS note TEXT;
@@ -0,0 +1,7 @@
+-- single row holding the secret set by /totp/rotate, which supersedes
+-- KSERVER_SECRET once present
+CREATE TABLE IF NOT EXISTS totp_secret (
+    id BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+    secret TEXT NOT NULL,
+    rotated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
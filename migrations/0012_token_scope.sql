@@ -0,0 +1,2 @@
+-- tokens issued before scopes existed keep full access
+ALTER TABLE auth_tokens ADD COLUMN IF NOT EXISTS scope TEXT NOT NULL DEFAULT 'readwrite';
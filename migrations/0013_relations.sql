@@ -0,0 +1,9 @@
+-- related_id is the anime_id's `kind`, e.g. its sequel; every link is stored
+-- in both directions so lookups from either side are a single scan
+CREATE TABLE IF NOT EXISTS relations (
+    anime_id INT NOT NULL REFERENCES anime_state (anime_id) ON DELETE CASCADE,
+    related_id INT NOT NULL REFERENCES anime_state (anime_id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    PRIMARY KEY (anime_id, related_id),
+    CHECK (anime_id <> related_id)
+);
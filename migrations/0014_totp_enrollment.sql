@@ -0,0 +1,13 @@
+-- single row present once an authenticator has been enrolled, i.e. after the
+-- first successful login; /totp/qr stays closed from then on. Deleting the
+-- row reopens enrollment. Existing installs that already have sessions or a
+-- rotated secret count as enrolled.
+CREATE TABLE IF NOT EXISTS totp_enrollment (
+    id BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+    enrolled_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+INSERT INTO totp_enrollment (id)
+SELECT true
+WHERE EXISTS (SELECT 1 FROM auth_tokens) OR EXISTS (SELECT 1 FROM totp_secret)
+ON CONFLICT (id) DO NOTHING;
@@ -0,0 +1,54 @@
+use std::sync::OnceLock;
+
+use futures_util::future::join_all;
+use reqwest::{Client, StatusCode};
+use tokio::sync::Semaphore;
+use tracing::info;
+
+use crate::model::AnimeItem;
+
+use super::db_error::DbError;
+
+const BANGUMI_API: &str = "https://api.bgm.tv/v0";
+// Bangumi throttles bursts, so a batch only has a few requests in flight
+const MAX_CONCURRENT_FETCHES: usize = 4;
+
+type Result<T> = std::result::Result<T, DbError>;
+
+fn client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    // Bangumi rejects requests without a descriptive User-Agent
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .user_agent(concat!("KevinT3Hu/kserver/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .unwrap()
+    })
+}
+
+pub async fn fetch_subject(id: i32) -> Result<AnimeItem> {
+    info!("Fetching bangumi subject {}", id);
+    let response = client()
+        .get(format!("{BANGUMI_API}/subjects/{id}"))
+        .send()
+        .await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Err(DbError::AnimeNotFound(id));
+    }
+    let item = response.error_for_status()?.json().await?;
+    Ok(item)
+}
+
+/// Fetches every subject in `ids`, keeping each id's outcome so one bad id
+/// doesn't sink the rest
+pub async fn fetch_subjects(ids: &[i32]) -> Vec<(i32, Result<AnimeItem>)> {
+    let permits = Semaphore::new(MAX_CONCURRENT_FETCHES);
+    let fetches = ids.iter().map(|&id| {
+        let permits = &permits;
+        async move {
+            let _permit = permits.acquire().await.unwrap();
+            (id, fetch_subject(id).await)
+        }
+    });
+    join_all(fetches).await
+}
@@ -1,224 +1,1937 @@
-use serde_json::Value;
-use std::{collections::HashSet, sync::Arc};
-use tokio_postgres::NoTls;
-use tracing::info;
+use std::{
+    collections::HashSet,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::model::{AnimeItem, AnimeState, WatchList};
+use async_trait::async_trait;
+use chrono::{Datelike, Utc};
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, PoolError, RecyclingMethod};
+use moka::future::Cache;
+use tokio::sync::broadcast;
+use tokio_postgres::{config::SslMode, error::SqlState, NoTls};
+use tracing::{debug, info, instrument, warn};
 
-use super::db_error::DbError;
+use crate::{
+    model::{
+        request::{ImportMode, OnConflict, SortKey, SortOrder},
+        AnimeItem, AnimeRelations, AnimeState, BacklogItem, Float, LibraryEvent, LibraryExport,
+        PoolStatus, Relation, RelationKind, Season, Stats, Tag, TokenScope, WatchList,
+        WatchListExpanded, WatchListSummary, WatchStatus, WatchedEpisodes, YearInReview,
+        EXPORT_VERSION, MAX_EPISODE,
+    },
+    startup_error, AuthToken,
+};
+
+use super::{db_error::DbError, migrations, notify, store::AnimeStore};
+
+const DEFAULT_POOL_SIZE: usize = 16;
+const DEFAULT_CACHE_TTL_SECS: u64 = 60;
+const DEFAULT_CACHE_CAPACITY: u64 = 64;
+const DEFAULT_ANIME_CACHE_CAPACITY: u64 = 1024;
+const DEFAULT_SLOW_QUERY_MS: u64 = 500;
+const YEAR_TOP_RATED: usize = 10;
+const MAX_CONNECT_ATTEMPTS: u32 = 5;
+const INITIAL_CONNECT_BACKOFF: Duration = Duration::from_millis(200);
 
 #[allow(clippy::module_name_repetitions)]
 #[derive(Clone)]
 pub struct DbHelper {
-    anime_db: Arc<tokio_postgres::Client>,
+    pool: Pool,
+    // Pages of /all keyed by their query, dropped wholesale on any anime_state write
+    all_animes: Cache<AllAnimesKey, Arc<Vec<AnimeState>>>,
+    // Single animes for detail lookups, dropped per id on writes to that anime
+    // and wholesale on writes that may touch several
+    animes: Cache<i32, Arc<AnimeState>>,
+    // Bumped by every anime_state write, see `fill_cache`
+    cache_generation: Arc<AtomicU64>,
+    events: broadcast::Sender<LibraryEvent>,
+    slow_query: Duration,
+}
+
+// Warns on drop when the method took longer than the threshold, so early
+// returns through `?` are timed as well
+struct QueryTimer {
+    method: &'static str,
+    start: Instant,
+    threshold: Duration,
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        if elapsed >= self.threshold {
+            warn!("Slow query in {}: {}ms", self.method, elapsed.as_millis());
+        }
+    }
 }
 
+type AllAnimesKey = (i64, i64, Option<SortKey>, SortOrder);
+
 type Result<T> = std::result::Result<T, DbError>;
 
+// Only ever built from the enums, so it is safe to splice into the query
+fn order_by_clause(sort: Option<SortKey>, order: SortOrder) -> String {
+    let direction = match order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+    match sort {
+        None => "anime_id".to_owned(),
+        Some(SortKey::Rating) => format!("rating {direction} NULLS LAST, anime_id"),
+        Some(SortKey::Date) => format!("anime_item->>'date' {direction} NULLS LAST, anime_id"),
+        Some(SortKey::Name) => format!("anime_item->>'name' {direction}, anime_id"),
+    }
+}
+
+fn watch_list_conflict(e: tokio_postgres::Error, watch_list_name: &str) -> DbError {
+    if e.code() == Some(&SqlState::UNIQUE_VIOLATION) {
+        DbError::WatchListExists(watch_list_name.to_string())
+    } else {
+        e.into()
+    }
+}
+
+// PG_URI wins when set; otherwise the libpq-style PGHOST/PGPORT/PGUSER/
+// PGPASSWORD/PGDATABASE variables are assembled into a config
+fn pg_config_from_env() -> tokio_postgres::Config {
+    if let Ok(uri) = std::env::var("PG_URI") {
+        return uri
+            .parse()
+            .unwrap_or_else(|e| startup_error(&format!("Invalid PG_URI: {e}")));
+    }
+
+    let Ok(host) = std::env::var("PGHOST") else {
+        startup_error("No database configured: set PG_URI or PGHOST/PGUSER/PGDATABASE");
+    };
+    let mut pg_config = tokio_postgres::Config::new();
+    pg_config.host(&host);
+    if let Ok(port) = std::env::var("PGPORT") {
+        let port = port
+            .parse()
+            .unwrap_or_else(|e| startup_error(&format!("Invalid PGPORT {port:?}: {e}")));
+        pg_config.port(port);
+    }
+    match std::env::var("PGUSER") {
+        Ok(user) => pg_config.user(&user),
+        Err(_) => startup_error("PGHOST is set but PGUSER is missing"),
+    };
+    if let Ok(password) = std::env::var("PGPASSWORD") {
+        pg_config.password(password);
+    }
+    if let Ok(dbname) = std::env::var("PGDATABASE") {
+        pg_config.dbname(&dbname);
+    }
+    pg_config
+}
+
+#[cfg(feature = "tls")]
+fn tls_manager(pg_config: tokio_postgres::Config, manager_config: ManagerConfig) -> Manager {
+    info!("Connecting to Postgres over TLS");
+    Manager::from_config(pg_config, super::tls::make_connector(), manager_config)
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_manager(_: tokio_postgres::Config, _: ManagerConfig) -> Manager {
+    startup_error("PG_SSLMODE=require but kserver was built without the `tls` feature");
+}
+
+fn create_pool(mut pg_config: tokio_postgres::Config) -> Pool {
+    let pool_size = std::env::var("PG_POOL_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_POOL_SIZE);
+    info!("Creating connection pool with size {}", pool_size);
+    let manager_config = ManagerConfig {
+        recycling_method: RecyclingMethod::Fast,
+    };
+    let manager = if std::env::var("PG_SSLMODE").is_ok_and(|mode| mode == "require") {
+        pg_config.ssl_mode(SslMode::Require);
+        tls_manager(pg_config, manager_config)
+    } else {
+        Manager::from_config(pg_config, NoTls, manager_config)
+    };
+    Pool::builder(manager)
+        .max_size(pool_size)
+        .build()
+        .unwrap_or_else(|e| startup_error(&format!("Cannot create connection pool: {e}")))
+}
+
 impl DbHelper {
-    pub async fn new() -> Self {
+    pub async fn new(events: broadcast::Sender<LibraryEvent>) -> Self {
         info!("Start creating database helper...");
-        let (client, connection) =
-            tokio_postgres::connect(&std::env::var("PG_URI").unwrap(), NoTls)
-                .await
-                .unwrap();
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                panic!("connection error: {e}");
-            }
-        });
+        Self::connect(pg_config_from_env(), events).await
+    }
+
+    // Builds the pool and runs migrations against an explicit config, so a
+    // throwaway database can be targeted without touching the environment
+    pub async fn connect(
+        pg_config: tokio_postgres::Config,
+        events: broadcast::Sender<LibraryEvent>,
+    ) -> Self {
+        let pool = create_pool(pg_config);
+        let mut client = pool
+            .get()
+            .await
+            .unwrap_or_else(|e| startup_error(&format!("Cannot connect to Postgres: {e}")));
+        migrations::run(&mut client)
+            .await
+            .unwrap_or_else(|e| startup_error(&format!("Cannot apply migrations: {e}")));
+        let cache_ttl = Duration::from_secs(
+            std::env::var("KSERVER_CACHE_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_CACHE_TTL_SECS),
+        );
+        let cache_capacity = std::env::var("KSERVER_CACHE_CAPACITY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_CACHE_CAPACITY);
+        info!(
+            "Caching /all pages: capacity {}, ttl {:?}",
+            cache_capacity, cache_ttl
+        );
+        let all_animes = Cache::builder()
+            .max_capacity(cache_capacity)
+            .time_to_live(cache_ttl)
+            .build();
+        let anime_cache_capacity = std::env::var("KSERVER_ANIME_CACHE_CAPACITY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_ANIME_CACHE_CAPACITY);
+        info!(
+            "Caching single animes: capacity {}, ttl {:?}",
+            anime_cache_capacity, cache_ttl
+        );
+        let animes = Cache::builder()
+            .max_capacity(anime_cache_capacity)
+            .time_to_live(cache_ttl)
+            .build();
+        let slow_query = Duration::from_millis(
+            std::env::var("KSERVER_SLOW_QUERY_MS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_SLOW_QUERY_MS),
+        );
         info!("Database helper created");
         Self {
-            anime_db: Arc::new(client),
+            pool,
+            all_animes,
+            animes,
+            cache_generation: Arc::new(AtomicU64::new(0)),
+            events,
+            slow_query,
+        }
+    }
+
+    /// Applies pending migrations to the configured database without starting
+    /// anything else
+    pub async fn migrate() -> Result<()> {
+        let pool = create_pool(pg_config_from_env());
+        let mut client = pool.get().await?;
+        migrations::run(&mut client).await?;
+        Ok(())
+    }
+
+    fn timer(&self, method: &'static str) -> QueryTimer {
+        QueryTimer {
+            method,
+            start: Instant::now(),
+            threshold: self.slow_query,
+        }
+    }
+
+    // Fast recycling drops pooled clients whose connection has closed, so a
+    // dropped connection is replaced on the next checkout. Opening that new
+    // connection is retried with backoff to ride out a restarting database
+    async fn client(&self) -> Result<Object> {
+        let mut backoff = INITIAL_CONNECT_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match self.pool.get().await {
+                Ok(client) => return Ok(client),
+                Err(PoolError::Backend(e)) if attempt < MAX_CONNECT_ATTEMPTS => {
+                    warn!(
+                        "Connecting to database failed (attempt {}/{}): {}, retrying in {:?}",
+                        attempt, MAX_CONNECT_ATTEMPTS, e, backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    // Applies `edit` to the anime's tags under a row lock; it returns whether
+    // anything changed so no-op edits skip the write
+    async fn edit_tags(
+        &self,
+        anime_id: i32,
+        edit: impl FnOnce(&mut Vec<Tag>) -> bool + Send,
+    ) -> Result<()> {
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        let rows = transaction
+            .query(
+                "SELECT anime_item FROM anime_state WHERE anime_id = $1 FOR UPDATE",
+                &[&anime_id],
+            )
+            .await?;
+        let Some(row) = rows.first() else {
+            return Err(DbError::AnimeNotFound(anime_id));
+        };
+        let mut anime_item: AnimeItem = serde_json::from_value(row.get(0)).map_err(|e| {
+            warn!("Malformed anime_item for anime {}: {}", anime_id, e);
+            DbError::MalformedAnimeItem(anime_id)
+        })?;
+        if !edit(anime_item.tags.get_or_insert_with(Vec::new)) {
+            return Ok(());
         }
+        let item_jsonb = serde_json::to_value(&anime_item).unwrap();
+        transaction
+            .execute(
+                "UPDATE anime_state SET anime_item = $1, last_updated = now() WHERE anime_id = $2",
+                &[&item_jsonb, &anime_id],
+            )
+            .await?;
+        transaction.commit().await?;
+        self.anime_changed(LibraryEvent::anime("anime_update", anime_id))
+            .await;
+        Ok(())
     }
 
-    pub async fn get_all_list(&self) -> Result<Vec<WatchList>> {
-        let client = self.anime_db.clone();
-        let rows = client.query("SELECT * FROM anime_list", &[]).await?;
+    // Every anime_state write goes through here so the caches are never stale
+    // and subscribers hear about the change
+    async fn anime_changed(&self, event: LibraryEvent) {
+        // bumped before invalidating, for `fill_cache`
+        self.cache_generation.fetch_add(1, Ordering::AcqRel);
+        self.all_animes.invalidate_all();
+        match event.anime_id {
+            Some(anime_id) => self.animes.invalidate(&anime_id).await,
+            None => self.animes.invalidate_all(),
+        }
+        self.publish(event);
+    }
+
+    // Taken before a cached read queries the database
+    fn cache_generation(&self) -> u64 {
+        self.cache_generation.load(Ordering::Acquire)
+    }
+
+    // A read that raced a write may hold the state from before it, and filling
+    // the cache after the write invalidated it would serve that until the ttl
+    // runs out. The fill is dropped if any write landed since `generation`.
+    // Checking again after the insert covers a write that bumps and invalidates
+    // between the first check and the insert.
+    async fn fill_cache<K, V>(&self, cache: &Cache<K, Arc<V>>, key: K, value: V, generation: u64)
+    where
+        K: Hash + Eq + Clone + Send + Sync + 'static,
+        V: Send + Sync + 'static,
+    {
+        if self.cache_generation() != generation {
+            return;
+        }
+        cache.insert(key.clone(), Arc::new(value)).await;
+        if self.cache_generation() != generation {
+            cache.invalidate(&key).await;
+        }
+    }
+
+    fn publish(&self, event: LibraryEvent) {
+        // an error only means nobody is listening right now
+        let _ = self.events.send(event);
+    }
+}
+
+#[async_trait]
+impl AnimeStore for DbHelper {
+    #[instrument(level = "debug", skip_all)]
+    async fn ping(&self) -> Result<()> {
+        let _timer = self.timer("ping");
+        // no retries, readiness should report an outage right away
+        let client = self.pool.get().await?;
+        client.query_one("SELECT 1", &[]).await?;
+        Ok(())
+    }
+
+    fn pool_status(&self) -> PoolStatus {
+        let status = self.pool.status();
+        PoolStatus {
+            max_size: status.max_size,
+            size: status.size,
+            available: status.available,
+            waiting: status.waiting,
+        }
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_all_list(&self, archived: Option<bool>) -> Result<Vec<WatchList>> {
+        let _timer = self.timer("get_all_list");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT * FROM anime_list WHERE $1::bool IS NULL OR archived = $1",
+                &[&archived],
+            )
+            .await?;
         let rows = rows.iter().map(std::convert::Into::into).collect();
 
         Ok(rows)
     }
 
-    pub async fn query_anime_by_id(&self, anime_id: i32) -> Result<AnimeState> {
-        let client = self.anime_db.clone();
+    #[instrument(level = "debug", skip_all)]
+    async fn get_all_list_summaries(
+        &self,
+        archived: Option<bool>,
+    ) -> Result<Vec<WatchListSummary>> {
+        let _timer = self.timer("get_all_list_summaries");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT title, archived, COALESCE(array_length(animes, 1), 0) FROM anime_list \
+                 WHERE $1::bool IS NULL OR archived = $1",
+                &[&archived],
+            )
+            .await?;
+        Ok(rows.iter().map(std::convert::Into::into).collect())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_anime_by_id(&self, anime_id: i32) -> Result<AnimeState> {
+        let _timer = self.timer("query_anime_by_id");
+        if let Some(cached) = self.animes.get(&anime_id).await {
+            return Ok(cached.as_ref().clone());
+        }
+        let generation = self.cache_generation();
+        let client = self.client().await?;
         let rows = client
             .query(
                 "SELECT * FROM anime_state WHERE anime_id = $1",
                 &[&anime_id],
             )
             .await?;
-        let ret = (&rows[0]).into();
-        Ok(ret)
+        let Some(row) = rows.first() else {
+            return Err(DbError::AnimeNotFound(anime_id));
+        };
+        let state: AnimeState = row.try_into()?;
+        self.fill_cache(&self.animes, anime_id, state.clone(), generation)
+            .await;
+        Ok(state)
     }
 
-    pub async fn insert_anime_item(&self, anime_item: AnimeItem) -> Result<()> {
-        let client = self.anime_db.clone();
+    #[instrument(level = "debug", skip_all)]
+    async fn insert_anime_item(&self, anime_item: AnimeItem) -> Result<()> {
+        let _timer = self.timer("insert_anime_item");
+        let client = self.client().await?;
         let item_jsonb = serde_json::to_value(&anime_item).unwrap();
         client
             .execute(
-                "INSERT INTO anime_state (anime_id,anime_item) VALUES($1,$2)",
+                "INSERT INTO anime_state (anime_id,anime_item) VALUES($1,$2)
+                ON CONFLICT (anime_id) DO UPDATE SET anime_item = EXCLUDED.anime_item",
                 &[&anime_item.id, &item_jsonb],
             )
             .await?;
+        self.anime_changed(LibraryEvent::anime("anime_update", anime_item.id))
+            .await;
         Ok(())
     }
 
-    pub async fn update_episode_watched_state(
+    #[instrument(level = "debug", skip_all)]
+    async fn insert_anime_items(&self, anime_items: &[AnimeItem]) -> Result<()> {
+        let _timer = self.timer("insert_anime_items");
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        let stmt = transaction
+            .prepare(
+                "INSERT INTO anime_state (anime_id,anime_item) VALUES($1,$2)
+                ON CONFLICT (anime_id) DO UPDATE SET anime_item = EXCLUDED.anime_item",
+            )
+            .await?;
+        for anime_item in anime_items {
+            let item_jsonb = serde_json::to_value(anime_item).unwrap();
+            transaction
+                .execute(&stmt, &[&anime_item.id, &item_jsonb])
+                .await?;
+        }
+        transaction.commit().await?;
+        for anime_item in anime_items {
+            self.anime_changed(LibraryEvent::anime("anime_update", anime_item.id))
+                .await;
+        }
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn insert_new_anime_item(&self, anime_item: AnimeItem) -> Result<()> {
+        let _timer = self.timer("insert_new_anime_item");
+        let client = self.client().await?;
+        let item_jsonb = serde_json::to_value(&anime_item).unwrap();
+        let affected = client
+            .execute(
+                "INSERT INTO anime_state (anime_id,anime_item) VALUES($1,$2)
+                ON CONFLICT (anime_id) DO NOTHING",
+                &[&anime_item.id, &item_jsonb],
+            )
+            .await?;
+        if affected == 0 {
+            return Err(DbError::AnimeExists(anime_item.id));
+        }
+        self.anime_changed(LibraryEvent::anime("anime_update", anime_item.id))
+            .await;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn update_anime_item(&self, anime_id: i32, anime_item: AnimeItem) -> Result<()> {
+        let _timer = self.timer("update_anime_item");
+        let client = self.client().await?;
+        let item_jsonb = serde_json::to_value(&anime_item).unwrap();
+        let affected = client
+            .execute(
+                "UPDATE anime_state SET anime_item = $1 WHERE anime_id = $2",
+                &[&item_jsonb, &anime_id],
+            )
+            .await?;
+        if affected == 0 {
+            return Err(DbError::AnimeNotFound(anime_id));
+        }
+        self.anime_changed(LibraryEvent::anime("anime_update", anime_id))
+            .await;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn update_episode_watched_state(
         &self,
         anime_id: i32,
-        ep: i32,
+        ep: Float,
         watched: bool,
-    ) -> Result<()> {
-        let client = self.anime_db.clone();
-        let watched_episode = client
+    ) -> Result<AnimeState> {
+        // timed by update_episodes_watched_state
+        self.update_episodes_watched_state(anime_id, &[ep], watched)
+            .await
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn update_episodes_watched_state(
+        &self,
+        anime_id: i32,
+        eps: &[Float],
+        watched: bool,
+    ) -> Result<AnimeState> {
+        let _timer = self.timer("update_episodes_watched_state");
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        // lock the row so concurrent updates can't overwrite each other's episodes
+        let rows = transaction
             .query(
-                "SELECT watched_episodes FROM anime_state WHERE anime_id = $1",
+                "SELECT watched_episodes, anime_item FROM anime_state \
+                 WHERE anime_id = $1 FOR UPDATE",
                 &[&anime_id],
             )
             .await?;
-        let watched_episode: Value = watched_episode[0].get(0);
-        let mut watched_episode: HashSet<_> = serde_json::from_value(watched_episode).unwrap();
-        if watched {
-            watched_episode.insert(ep);
-        } else {
-            watched_episode.remove(&ep);
+        let Some(row) = rows.first() else {
+            return Err(DbError::AnimeNotFound(anime_id));
+        };
+        // writing back an empty set would wipe whatever the malformed value held
+        let mut watched_episode = WatchedEpisodes::from_column(row.get(0)).map_err(|e| {
+            warn!("Malformed watched_episodes for anime {}: {}", anime_id, e);
+            DbError::MalformedWatchedEpisodes(anime_id)
+        })?;
+        let anime_item: AnimeItem = serde_json::from_value(row.get(1)).map_err(|e| {
+            warn!("Malformed anime_item for anime {}: {}", anime_id, e);
+            DbError::MalformedAnimeItem(anime_id)
+        })?;
+        let total = usize::try_from(anime_item.total_episodes).unwrap_or_default();
+        let was_complete = watched_episode.whole_count() >= total;
+        for &ep in eps {
+            if watched {
+                watched_episode.insert(ep);
+            } else {
+                watched_episode.remove(ep);
+            }
         }
 
+        let now_complete = watched_episode.whole_count() >= total;
         let watched_episode = serde_json::to_value(&watched_episode).unwrap();
-        client
-            .execute(
-                "UPDATE anime_state SET watched_episodes = $1 WHERE anime_id = $2",
+        let row = transaction
+            .query_one(
+                "UPDATE anime_state SET watched_episodes = $1, last_updated = now()
+                WHERE anime_id = $2 RETURNING *",
                 &[&watched_episode, &anime_id],
             )
             .await?;
+        transaction.commit().await?;
+        if total > 0 && !was_complete && now_complete {
+            notify::notify_completion(&anime_item);
+        }
 
-        Ok(())
+        self.anime_changed(LibraryEvent::anime("episode_update", anime_id))
+            .await;
+        (&row).try_into()
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn mark_all_watched(&self, anime_id: i32) -> Result<AnimeState> {
+        let _timer = self.timer("mark_all_watched");
+        let state = self.query_anime_by_id(anime_id).await?;
+        let total = state.anime_item.total_episodes;
+        // the count comes from Bangumi or an import and is allocated in full
+        if !(1..=MAX_EPISODE).contains(&total) {
+            return Err(DbError::EpisodeCountOutOfRange(anime_id, total));
+        }
+        let eps: Vec<Float> = (1..=total).map(Float::Int).collect();
+        self.update_episodes_watched_state(anime_id, &eps, true)
+            .await
     }
 
-    pub async fn add_item_to_watch_list(&self, anime_id: i32, watch_list_name: &str) -> Result<()> {
-        let client = self.anime_db.clone();
+    #[instrument(level = "debug", skip_all)]
+    async fn unmark_all(&self, anime_id: i32) -> Result<AnimeState> {
+        let _timer = self.timer("unmark_all");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "UPDATE anime_state SET watched_episodes = '{}', last_updated = now()
+                WHERE anime_id = $1 RETURNING *",
+                &[&anime_id],
+            )
+            .await?;
+        let Some(row) = rows.first() else {
+            return Err(DbError::AnimeNotFound(anime_id));
+        };
+        self.anime_changed(LibraryEvent::anime("episode_update", anime_id))
+            .await;
+        row.try_into()
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn add_item_to_watch_list(&self, anime_id: i32, watch_list_name: &str) -> Result<()> {
+        let _timer = self.timer("add_item_to_watch_list");
+        let client = self.client().await?;
+        // the CASE keeps the update idempotent while still matching the row,
+        // so zero affected rows means the list itself is missing
         let stmt = client
-            .prepare("UPDATE anime_list SET animes = array_append(animes, $1) WHERE title = $2")
+            .prepare(
+                "UPDATE anime_list SET animes = CASE
+                    WHEN animes @> ARRAY[$1::int] THEN animes
+                    ELSE array_append(animes, $1)
+                END WHERE title = $2",
+            )
             .await?;
-        client
+        let affected = client
             .execute(&stmt, &[&anime_id, &watch_list_name])
             .await?;
+        if affected == 0 {
+            return Err(DbError::WatchListNotFound(watch_list_name.to_string()));
+        }
+        self.publish(LibraryEvent::watch_list(watch_list_name));
         Ok(())
     }
 
-    pub async fn add_new_watch_list(&self, watch_list_name: &str) -> Result<()> {
-        let client = self.anime_db.clone();
+    #[instrument(level = "debug", skip_all)]
+    async fn move_anime_between_lists(&self, anime_id: i32, from: &str, to: &str) -> Result<()> {
+        let _timer = self.timer("move_anime_between_lists");
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        let affected = transaction
+            .execute(
+                "UPDATE anime_list SET animes = array_remove(animes, $1) WHERE title = $2",
+                &[&anime_id, &from],
+            )
+            .await?;
+        if affected == 0 {
+            return Err(DbError::WatchListNotFound(from.to_string()));
+        }
+        let affected = transaction
+            .execute(
+                "UPDATE anime_list SET animes = CASE
+                    WHEN animes @> ARRAY[$1::int] THEN animes
+                    ELSE array_append(animes, $1)
+                END WHERE title = $2",
+                &[&anime_id, &to],
+            )
+            .await?;
+        if affected == 0 {
+            return Err(DbError::WatchListNotFound(to.to_string()));
+        }
+        transaction.commit().await?;
+        self.publish(LibraryEvent::watch_list(to));
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn add_new_watch_list(&self, watch_list_name: &str) -> Result<()> {
+        let _timer = self.timer("add_new_watch_list");
+        let client = self.client().await?;
         let animes: Vec<i32> = Vec::new();
         client
             .execute(
                 "INSERT INTO anime_list VALUES($1,$2,$3)",
                 &[&watch_list_name, &false, &animes],
             )
+            .await
+            .map_err(|e| watch_list_conflict(e, watch_list_name))?;
+        self.publish(LibraryEvent::watch_list(watch_list_name));
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn add_watch_lists(
+        &self,
+        watch_list_names: &[String],
+        on_conflict: OnConflict,
+    ) -> Result<Vec<String>> {
+        let _timer = self.timer("add_watch_lists");
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        let stmt = transaction
+            .prepare(match on_conflict {
+                OnConflict::Error => "INSERT INTO anime_list VALUES($1,$2,$3)",
+                OnConflict::Skip => {
+                    "INSERT INTO anime_list VALUES($1,$2,$3) ON CONFLICT (title) DO NOTHING"
+                }
+            })
             .await?;
+        let animes: Vec<i32> = Vec::new();
+        let mut created = Vec::new();
+        for name in watch_list_names {
+            let affected = transaction
+                .execute(&stmt, &[name, &false, &animes])
+                .await
+                .map_err(|e| watch_list_conflict(e, name))?;
+            if affected > 0 {
+                created.push(name.clone());
+            }
+        }
+        transaction.commit().await?;
+        for name in &created {
+            self.publish(LibraryEvent::watch_list(name));
+        }
+        Ok(created)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn rename_watch_list(&self, old_name: &str, new_name: &str) -> Result<()> {
+        let _timer = self.timer("rename_watch_list");
+        let client = self.client().await?;
+        let affected = client
+            .execute(
+                "UPDATE anime_list SET title = $1 WHERE title = $2",
+                &[&new_name, &old_name],
+            )
+            .await
+            .map_err(|e| watch_list_conflict(e, new_name))?;
+        if affected == 0 {
+            return Err(DbError::WatchListNotFound(old_name.to_string()));
+        }
+        self.publish(LibraryEvent::watch_list(new_name));
         Ok(())
     }
 
-    pub async fn update_watch_list_archive_state(
+    #[instrument(level = "debug", skip_all)]
+    async fn update_watch_list_archive_state(
         &self,
         watch_list_name: &str,
         archived: bool,
     ) -> Result<()> {
-        let client = self.anime_db.clone();
+        let _timer = self.timer("update_watch_list_archive_state");
+        let client = self.client().await?;
         let stmt = client
             .prepare("UPDATE anime_list SET archived = $1 WHERE title = $2")
             .await?;
-        client
+        let affected = client
             .execute(&stmt, &[&archived, &watch_list_name])
             .await?;
+        if affected == 0 {
+            return Err(DbError::WatchListNotFound(watch_list_name.to_string()));
+        }
+        self.publish(LibraryEvent::watch_list(watch_list_name));
         Ok(())
     }
 
-    pub async fn update_anime_visibility(&self, anime_id: i32, visibility: bool) -> Result<()> {
-        let client = self.anime_db.clone();
+    #[instrument(level = "debug", skip_all)]
+    async fn update_anime_visibility(&self, anime_id: i32, visibility: bool) -> Result<AnimeState> {
+        let _timer = self.timer("update_anime_visibility");
+        let client = self.client().await?;
         let stmt = client
-            .prepare("UPDATE anime_state SET visible = $1 WHERE anime_id = $2")
+            .prepare("UPDATE anime_state SET visible = $1 WHERE anime_id = $2 RETURNING *")
             .await?;
-        client.execute(&stmt, &[&visibility, &anime_id]).await?;
+        let rows = client.query(&stmt, &[&visibility, &anime_id]).await?;
+        let Some(row) = rows.first() else {
+            return Err(DbError::AnimeNotFound(anime_id));
+        };
+        self.anime_changed(LibraryEvent::anime("anime_update", anime_id))
+            .await;
+        row.try_into()
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn update_watch_list_visibility(
+        &self,
+        watch_list_name: &str,
+        visible: bool,
+    ) -> Result<()> {
+        let _timer = self.timer("update_watch_list_visibility");
+        let client = self.client().await?;
+        // one statement so the members can't change between reading the list
+        // and updating them; the list is selected again to tell a missing
+        // list from an empty one
+        let row = client
+            .query_one(
+                "WITH list AS (SELECT animes FROM anime_list WHERE title = $2),
+                updated AS (
+                    UPDATE anime_state SET visible = $1
+                    WHERE anime_id = ANY((SELECT animes FROM list)::int[])
+                )
+                SELECT EXISTS (SELECT 1 FROM list)",
+                &[&visible, &watch_list_name],
+            )
+            .await?;
+        if !row.get::<_, bool>(0) {
+            return Err(DbError::WatchListNotFound(watch_list_name.to_string()));
+        }
+        self.anime_changed(LibraryEvent::watch_list(watch_list_name))
+            .await;
         Ok(())
     }
 
-    pub async fn delete_watch_list(&self, watch_list_name: &str) -> Result<()> {
-        let client = self.anime_db.clone();
-        client
+    #[instrument(level = "debug", skip_all)]
+    async fn archive_and_hide(&self, watch_list_name: &str) -> Result<()> {
+        let _timer = self.timer("archive_and_hide");
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        let rows = transaction
+            .query(
+                "UPDATE anime_list SET archived = true WHERE title = $1 RETURNING animes",
+                &[&watch_list_name],
+            )
+            .await?;
+        let Some(row) = rows.first() else {
+            return Err(DbError::WatchListNotFound(watch_list_name.to_string()));
+        };
+        let animes: Vec<i32> = row.get(0);
+        transaction
+            .execute(
+                "UPDATE anime_state SET visible = false WHERE anime_id = ANY($1)",
+                &[&animes],
+            )
+            .await?;
+        transaction.commit().await?;
+        self.anime_changed(LibraryEvent::watch_list(watch_list_name))
+            .await;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn update_anime_favorite(&self, anime_id: i32, favorite: bool) -> Result<AnimeState> {
+        let _timer = self.timer("update_anime_favorite");
+        let client = self.client().await?;
+        let stmt = client
+            .prepare("UPDATE anime_state SET favorite = $1 WHERE anime_id = $2 RETURNING *")
+            .await?;
+        let rows = client.query(&stmt, &[&favorite, &anime_id]).await?;
+        let Some(row) = rows.first() else {
+            return Err(DbError::AnimeNotFound(anime_id));
+        };
+        self.anime_changed(LibraryEvent::anime("anime_update", anime_id))
+            .await;
+        row.try_into()
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn update_anime_note<'a>(
+        &self,
+        anime_id: i32,
+        note: Option<&'a str>,
+    ) -> Result<AnimeState> {
+        let _timer = self.timer("update_anime_note");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "UPDATE anime_state SET note = $1, last_updated = now()
+                WHERE anime_id = $2 RETURNING *",
+                &[&note, &anime_id],
+            )
+            .await?;
+        let Some(row) = rows.first() else {
+            return Err(DbError::AnimeNotFound(anime_id));
+        };
+        self.anime_changed(LibraryEvent::anime("anime_update", anime_id))
+            .await;
+        row.try_into()
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_favorite_animes(&self) -> Result<Vec<AnimeState>> {
+        let _timer = self.timer("query_favorite_animes");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT * FROM anime_state WHERE favorite ORDER BY anime_id",
+                &[],
+            )
+            .await?;
+        let ret = rows
+            .iter()
+            .map(AnimeState::try_from)
+            .collect::<Result<_>>()?;
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_completed(&self) -> Result<Vec<AnimeState>> {
+        let _timer = self.timer("query_completed");
+        let client = self.client().await?;
+        // the explicit status wins over the watched set, so a rewatch in
+        // progress still counts and a fully watched but dropped show doesn't
+        let rows = client
+            .query(
+                "SELECT * FROM anime_state WHERE status = 'Completed'
+                ORDER BY last_updated DESC NULLS LAST, anime_id",
+                &[],
+            )
+            .await?;
+        let ret = rows
+            .iter()
+            .map(AnimeState::try_from)
+            .collect::<Result<_>>()?;
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn update_anime_status(&self, anime_id: i32, status: WatchStatus) -> Result<AnimeState> {
+        let _timer = self.timer("update_anime_status");
+        let client = self.client().await?;
+        let stmt = client
+            .prepare(
+                "UPDATE anime_state SET status = $1, last_updated = now()
+                WHERE anime_id = $2 RETURNING *",
+            )
+            .await?;
+        let rows = client.query(&stmt, &[&status.as_str(), &anime_id]).await?;
+        let Some(row) = rows.first() else {
+            return Err(DbError::AnimeNotFound(anime_id));
+        };
+        self.anime_changed(LibraryEvent::anime("anime_update", anime_id))
+            .await;
+        row.try_into()
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_animes_by_status(&self, status: WatchStatus) -> Result<Vec<AnimeState>> {
+        let _timer = self.timer("query_animes_by_status");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT * FROM anime_state WHERE status = $1 ORDER BY anime_id",
+                &[&status.as_str()],
+            )
+            .await?;
+        let ret = rows
+            .iter()
+            .map(AnimeState::try_from)
+            .collect::<Result<_>>()?;
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn delete_watch_list(&self, watch_list_name: &str) -> Result<()> {
+        let _timer = self.timer("delete_watch_list");
+        let client = self.client().await?;
+        let affected = client
             .execute(
                 "DELETE FROM anime_list WHERE title = $1",
                 &[&watch_list_name],
             )
             .await?;
+        if affected == 0 {
+            return Err(DbError::WatchListNotFound(watch_list_name.to_string()));
+        }
+        self.publish(LibraryEvent::watch_list(watch_list_name));
         Ok(())
     }
 
-    pub async fn query_anime_states_by_ids(&self, anime_ids: &Vec<i32>) -> Result<Vec<AnimeState>> {
-        let client = self.anime_db.clone();
+    #[instrument(level = "debug", skip_all)]
+    async fn query_anime_states_by_ids(&self, anime_ids: &[i32]) -> Result<Vec<AnimeState>> {
+        let _timer = self.timer("query_anime_states_by_ids");
+        let client = self.client().await?;
         let stmt = client
             .prepare("SELECT * FROM anime_state WHERE anime_id = ANY($1)")
             .await?;
         let rows = client.query(&stmt, &[&anime_ids]).await?;
-        let ret = rows.iter().map(std::convert::Into::into).collect();
+        let ret = rows
+            .iter()
+            .map(AnimeState::try_from)
+            .collect::<Result<_>>()?;
         Ok(ret)
     }
 
-    pub async fn delete_anime_state_from_watch_list(
+    #[instrument(level = "debug", skip_all)]
+    async fn delete_anime_state_from_watch_list(
         &self,
         anime_id: i32,
         watch_list_name: &str,
     ) -> Result<()> {
-        let client = self.anime_db.clone();
-        let stmt = client
+        let _timer = self.timer("delete_anime_state_from_watch_list");
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        // block concurrent list edits so no one can re-add the anime between
+        // the membership check and the delete below
+        transaction
+            .batch_execute("LOCK TABLE anime_list IN SHARE ROW EXCLUSIVE MODE")
+            .await?;
+        let stmt = transaction
             .prepare("UPDATE anime_list SET animes = array_remove(animes, $1) WHERE title = $2")
             .await?;
-        client
+        transaction
             .execute(&stmt, &[&anime_id, &watch_list_name])
             .await?;
 
         // if the anime is not in any watch list, delete it from anime_state
-        let stmt = client
-            .prepare("SELECT * FROM anime_list WHERE animes @> ARRAY[$1]")
+        let stmt = transaction
+            .prepare("SELECT * FROM anime_list WHERE animes @> ARRAY[$1::int]")
             .await?;
-        let rows = client.query(&stmt, &[&anime_id]).await?;
+        let rows = transaction.query(&stmt, &[&anime_id]).await?;
         if rows.is_empty() {
-            client
+            transaction
                 .execute("DELETE FROM anime_state WHERE anime_id = $1", &[&anime_id])
                 .await?;
         }
 
+        transaction.commit().await?;
+        self.anime_changed(LibraryEvent::watch_list(watch_list_name))
+            .await;
         Ok(())
     }
 
-    pub async fn query_all_animes(&self) -> Result<Vec<AnimeState>> {
-        let client = self.anime_db.clone();
-        let rows = client.query("SELECT * FROM anime_state", &[]).await?;
-        let ret = rows.iter().map(std::convert::Into::into).collect();
+    #[instrument(level = "debug", skip_all)]
+    async fn delete_anime_state(&self, anime_id: i32) -> Result<()> {
+        let _timer = self.timer("delete_anime_state");
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        transaction
+            .execute(
+                "UPDATE anime_list SET animes = array_remove(animes, $1)",
+                &[&anime_id],
+            )
+            .await?;
+        let affected = transaction
+            .execute("DELETE FROM anime_state WHERE anime_id = $1", &[&anime_id])
+            .await?;
+        if affected == 0 {
+            return Err(DbError::AnimeNotFound(anime_id));
+        }
+        transaction.commit().await?;
+        self.anime_changed(LibraryEvent::anime("anime_delete", anime_id))
+            .await;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_continue_watching(&self, limit: i64) -> Result<Vec<AnimeState>> {
+        let _timer = self.timer("query_continue_watching");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT * FROM anime_state
+                WHERE watched_count(watched_episodes) > 0
+                AND whole_watched_count(watched_episodes) < (anime_item->>'total_episodes')::int
+                ORDER BY last_updated DESC NULLS LAST
+                LIMIT $1",
+                &[&limit],
+            )
+            .await?;
+        let ret = rows
+            .iter()
+            .map(AnimeState::try_from)
+            .collect::<Result<_>>()?;
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_all_animes(
+        &self,
+        limit: i64,
+        offset: i64,
+        sort: Option<SortKey>,
+        order: SortOrder,
+    ) -> Result<Vec<AnimeState>> {
+        let _timer = self.timer("query_all_animes");
+        let key = (limit, offset, sort, order);
+        if let Some(cached) = self.all_animes.get(&key).await {
+            return Ok(cached.as_ref().clone());
+        }
+
+        let generation = self.cache_generation();
+        let client = self.client().await?;
+        let query = format!(
+            "SELECT * FROM anime_state ORDER BY {} LIMIT $1 OFFSET $2",
+            order_by_clause(sort, order)
+        );
+        let rows = client.query(&query, &[&limit, &offset]).await?;
+        let ret: Vec<AnimeState> = rows
+            .iter()
+            .map(AnimeState::try_from)
+            .collect::<Result<_>>()?;
+        self.fill_cache(&self.all_animes, key, ret.clone(), generation)
+            .await;
 
         Ok(ret)
     }
 
-    pub async fn get_watch_list(&self, watch_list_name: &str) -> Result<WatchList> {
-        let client = self.anime_db.clone();
+    #[instrument(level = "debug", skip_all)]
+    async fn reorder_ranking(&self, anime_ids: &[i32]) -> Result<()> {
+        let _timer = self.timer("reorder_ranking");
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        let existing: HashSet<i32> = transaction
+            .query(
+                "SELECT anime_id FROM anime_state WHERE anime_id = ANY($1) FOR UPDATE",
+                &[&anime_ids],
+            )
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        if let Some(&missing) = anime_ids.iter().find(|id| !existing.contains(id)) {
+            return Err(DbError::AnimeNotFound(missing));
+        }
+        // the list is the whole ranking, anything left out becomes unranked
+        transaction
+            .execute(
+                "UPDATE anime_state SET personal_rank = NULL WHERE personal_rank IS NOT NULL",
+                &[],
+            )
+            .await?;
+        transaction
+            .execute(
+                "UPDATE anime_state SET personal_rank = ranked.rank::int
+                FROM unnest($1::int[]) WITH ORDINALITY AS ranked(anime_id, rank)
+                WHERE anime_state.anime_id = ranked.anime_id",
+                &[&anime_ids],
+            )
+            .await?;
+        transaction.commit().await?;
+        self.anime_changed(LibraryEvent::ranking()).await;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_ranked(&self) -> Result<Vec<AnimeState>> {
+        let _timer = self.timer("query_ranked");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT * FROM anime_state WHERE personal_rank IS NOT NULL ORDER BY personal_rank",
+                &[],
+            )
+            .await?;
+        let ret = rows
+            .iter()
+            .map(AnimeState::try_from)
+            .collect::<Result<_>>()?;
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_top_rated(&self, limit: i64, order: SortOrder) -> Result<Vec<AnimeState>> {
+        let _timer = self.timer("query_top_rated");
+        let client = self.client().await?;
+        let query = format!(
+            "SELECT * FROM anime_state WHERE rating IS NOT NULL ORDER BY {} LIMIT $1",
+            order_by_clause(Some(SortKey::Rating), order)
+        );
+        let rows = client.query(&query, &[&limit]).await?;
+        let ret = rows
+            .iter()
+            .map(AnimeState::try_from)
+            .collect::<Result<_>>()?;
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn count_all_animes(&self) -> Result<i64> {
+        let _timer = self.timer("count_all_animes");
+        let client = self.client().await?;
+        let row = client
+            .query_one("SELECT COUNT(*) FROM anime_state", &[])
+            .await?;
+        Ok(row.get(0))
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_watch_list(&self, watch_list_name: &str) -> Result<WatchList> {
+        let _timer = self.timer("get_watch_list");
+        let client = self.client().await?;
         let rows = client
             .query(
                 "SELECT * FROM anime_list WHERE title = $1",
                 &[&watch_list_name],
             )
             .await?;
-        let ret = (&rows[0]).into();
-        Ok(ret)
+        let Some(row) = rows.first() else {
+            return Err(DbError::WatchListNotFound(watch_list_name.to_string()));
+        };
+        Ok(row.into())
     }
 
-    pub async fn update_anime_rating(&self, anime_id: i32, rating: i32) -> Result<()> {
-        let client = self.anime_db.clone();
+    #[instrument(level = "debug", skip_all)]
+    async fn get_watch_list_expanded(&self, watch_list_name: &str) -> Result<WatchListExpanded> {
+        let _timer = self.timer("get_watch_list_expanded");
+        let watch_list = self.get_watch_list(watch_list_name).await?;
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT * FROM anime_state WHERE anime_id = ANY($1) \
+                 ORDER BY array_position($1, anime_id)",
+                &[&watch_list.animes],
+            )
+            .await?;
+        Ok(WatchListExpanded {
+            title: watch_list.title,
+            archived: watch_list.archived,
+            animes: rows
+                .iter()
+                .map(AnimeState::try_from)
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn update_anime_rating(&self, anime_id: i32, rating: Float) -> Result<AnimeState> {
+        let _timer = self.timer("update_anime_rating");
+        let client = self.client().await?;
         let stmt = client
-            .prepare("UPDATE anime_state SET rating = $1 WHERE anime_id = $2")
+            .prepare("UPDATE anime_state SET rating = $1 WHERE anime_id = $2 RETURNING *")
+            .await?;
+        let rows = client.query(&stmt, &[&rating.value(), &anime_id]).await?;
+        let Some(row) = rows.first() else {
+            return Err(DbError::AnimeNotFound(anime_id));
+        };
+        self.anime_changed(LibraryEvent::anime("anime_update", anime_id))
+            .await;
+        row.try_into()
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_all_tokens(&self) -> Result<Vec<(AuthToken, TokenScope)>> {
+        let _timer = self.timer("get_all_tokens");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT token, scope FROM auth_tokens ORDER BY issued_at",
+                &[],
+            )
+            .await?;
+        let ret = rows
+            .iter()
+            .map(|row| {
+                let scope: &str = row.get(1);
+                // fail closed: a scope this build doesn't know grants no writes
+                let scope = scope.parse().unwrap_or_else(|e| {
+                    warn!("{}, treating the token as read-only", e);
+                    TokenScope::Read
+                });
+                (row.get(0), scope)
+            })
+            .collect();
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn insert_token(&self, token: &str, scope: TokenScope) -> Result<()> {
+        let _timer = self.timer("insert_token");
+        let client = self.client().await?;
+        client
+            .execute(
+                "INSERT INTO auth_tokens (token, scope) VALUES($1, $2)",
+                &[&token, &scope.as_str()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn delete_token(&self, token: &str) -> Result<()> {
+        let _timer = self.timer("delete_token");
+        let client = self.client().await?;
+        client
+            .execute("DELETE FROM auth_tokens WHERE token = $1", &[&token])
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn delete_all_tokens(&self) -> Result<()> {
+        let _timer = self.timer("delete_all_tokens");
+        let client = self.client().await?;
+        client.execute("DELETE FROM auth_tokens", &[]).await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_totp_secret(&self) -> Result<Option<String>> {
+        let _timer = self.timer("get_totp_secret");
+        let client = self.client().await?;
+        let row = client
+            .query_opt("SELECT secret FROM totp_secret", &[])
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn set_totp_secret(&self, secret: &str) -> Result<()> {
+        let _timer = self.timer("set_totp_secret");
+        let client = self.client().await?;
+        client
+            .execute(
+                "INSERT INTO totp_secret (secret) VALUES($1)
+                ON CONFLICT (id) DO UPDATE SET secret = EXCLUDED.secret, rotated_at = now()",
+                &[&secret],
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn is_totp_enrolled(&self) -> Result<bool> {
+        let _timer = self.timer("is_totp_enrolled");
+        let client = self.client().await?;
+        let row = client
+            .query_opt("SELECT 1 FROM totp_enrollment", &[])
+            .await?;
+        Ok(row.is_some())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn set_totp_enrolled(&self) -> Result<()> {
+        let _timer = self.timer("set_totp_enrolled");
+        let client = self.client().await?;
+        client
+            .execute(
+                "INSERT INTO totp_enrollment DEFAULT VALUES ON CONFLICT (id) DO NOTHING",
+                &[],
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn insert_share_token(&self, token: &str, watch_list_name: &str) -> Result<()> {
+        let _timer = self.timer("insert_share_token");
+        let client = self.client().await?;
+        client
+            .execute(
+                "INSERT INTO share_tokens (token, watch_list_name) VALUES ($1, $2)",
+                &[&token, &watch_list_name],
+            )
             .await
-            .unwrap();
-        client.execute(&stmt, &[&rating, &anime_id]).await.unwrap();
+            .map_err(|e| {
+                if e.code() == Some(&SqlState::FOREIGN_KEY_VIOLATION) {
+                    DbError::WatchListNotFound(watch_list_name.to_string())
+                } else {
+                    e.into()
+                }
+            })?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_shared_watch_list(&self, token: &str) -> Result<WatchListExpanded> {
+        let _timer = self.timer("get_shared_watch_list");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT watch_list_name FROM share_tokens WHERE token = $1",
+                &[&token],
+            )
+            .await?;
+        let Some(row) = rows.first() else {
+            return Err(DbError::ShareTokenNotFound(token.to_string()));
+        };
+        let watch_list_name: String = row.get(0);
+        let mut watch_list = self.get_watch_list_expanded(&watch_list_name).await?;
+        // the link is public: hidden animes and the owner's own notes and
+        // ranking stay out of it
+        watch_list.animes.retain(|anime| anime.visibility);
+        for anime in &mut watch_list.animes {
+            anime.note = None;
+            anime.personal_rank = None;
+        }
+        Ok(watch_list)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn delete_share_token(&self, token: &str) -> Result<()> {
+        let _timer = self.timer("delete_share_token");
+        let client = self.client().await?;
+        let affected = client
+            .execute("DELETE FROM share_tokens WHERE token = $1", &[&token])
+            .await?;
+        if affected == 0 {
+            return Err(DbError::ShareTokenNotFound(token.to_string()));
+        }
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn search_anime_by_name(&self, query: &str, limit: i64) -> Result<Vec<AnimeState>> {
+        let _timer = self.timer("search_anime_by_name");
+        let client = self.client().await?;
+        // escape LIKE wildcards so the query is matched literally
+        let escaped = query
+            .trim()
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        let pattern = format!("%{escaped}%");
+        let stmt = client
+            .prepare(
+                "SELECT * FROM anime_state
+                WHERE anime_item->>'name' ILIKE $1 OR anime_item->>'name_cn' ILIKE $1
+                ORDER BY anime_id LIMIT $2",
+            )
+            .await?;
+        let rows = client.query(&stmt, &[&pattern, &limit]).await?;
+        let ret = rows
+            .iter()
+            .map(AnimeState::try_from)
+            .collect::<Result<_>>()?;
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_all_tags(&self) -> Result<Vec<Tag>> {
+        let _timer = self.timer("get_all_tags");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT tag->>'name' AS name, SUM((tag->>'count')::int)::int AS total
+                FROM anime_state, jsonb_array_elements(
+                    CASE WHEN jsonb_typeof(anime_item->'tags') = 'array'
+                    THEN anime_item->'tags' ELSE '[]'::jsonb END
+                ) AS tag
+                GROUP BY name
+                ORDER BY total DESC, name",
+                &[],
+            )
+            .await?;
+        let ret = rows.iter().map(std::convert::Into::into).collect();
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn add_relation(&self, anime_id: i32, related_id: i32, kind: RelationKind) -> Result<()> {
+        let _timer = self.timer("add_relation");
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        let anime_ids = [anime_id, related_id];
+        let existing: HashSet<i32> = transaction
+            .query(
+                "SELECT anime_id FROM anime_state WHERE anime_id = ANY($1) FOR SHARE",
+                &[&&anime_ids[..]],
+            )
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        if let Some(&missing) = anime_ids.iter().find(|id| !existing.contains(id)) {
+            return Err(DbError::AnimeNotFound(missing));
+        }
+        // adding an existing pair again replaces its kind
+        let stmt = transaction
+            .prepare(
+                "INSERT INTO relations VALUES($1,$2,$3)
+                ON CONFLICT (anime_id, related_id) DO UPDATE SET kind = EXCLUDED.kind",
+            )
+            .await?;
+        transaction
+            .execute(&stmt, &[&anime_id, &related_id, &kind.as_str()])
+            .await?;
+        transaction
+            .execute(&stmt, &[&related_id, &anime_id, &kind.inverse().as_str()])
+            .await?;
+        transaction.commit().await?;
+        self.publish(LibraryEvent::anime("relation_update", anime_id));
+        self.publish(LibraryEvent::anime("relation_update", related_id));
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn delete_relation(&self, anime_id: i32, related_id: i32) -> Result<()> {
+        let _timer = self.timer("delete_relation");
+        let client = self.client().await?;
+        let affected = client
+            .execute(
+                "DELETE FROM relations
+                WHERE (anime_id = $1 AND related_id = $2) OR (anime_id = $2 AND related_id = $1)",
+                &[&anime_id, &related_id],
+            )
+            .await?;
+        if affected == 0 {
+            return Err(DbError::RelationNotFound(anime_id, related_id));
+        }
+        self.publish(LibraryEvent::anime("relation_update", anime_id));
+        self.publish(LibraryEvent::anime("relation_update", related_id));
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_relations(&self, anime_id: i32) -> Result<AnimeRelations> {
+        let _timer = self.timer("query_relations");
+        // an unknown anime is a 404 rather than an empty set of relations
+        self.query_anime_by_id(anime_id).await?;
+        let client = self.client().await?;
+        // kind goes last so the anime_state columns keep the positions
+        // AnimeState reads them from
+        let rows = client
+            .query(
+                "SELECT anime_state.*, relations.kind FROM relations
+                JOIN anime_state ON anime_state.anime_id = relations.related_id
+                WHERE relations.anime_id = $1
+                ORDER BY anime_item->>'date' NULLS LAST, anime_state.anime_id",
+                &[&anime_id],
+            )
+            .await?;
+        let mut relations = AnimeRelations::default();
+        for row in &rows {
+            let kind: &str = row.get("kind");
+            let group = match kind.parse() {
+                Ok(RelationKind::Sequel) => &mut relations.sequels,
+                Ok(RelationKind::Prequel) => &mut relations.prequels,
+                Ok(RelationKind::SideStory) => &mut relations.side_stories,
+                Err(e) => {
+                    warn!("Skipping relation of anime {}: {}", anime_id, e);
+                    continue;
+                }
+            };
+            group.push(row.try_into()?);
+        }
+        Ok(relations)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn add_tag(&self, anime_id: i32, name: &str) -> Result<()> {
+        let _timer = self.timer("add_tag");
+        self.edit_tags(anime_id, |tags| {
+            if tags.iter().any(|tag| tag.name == name) {
+                return false;
+            }
+            tags.push(Tag {
+                name: name.to_owned(),
+                count: 1,
+            });
+            true
+        })
+        .await
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn remove_tag(&self, anime_id: i32, name: &str) -> Result<()> {
+        let _timer = self.timer("remove_tag");
+        self.edit_tags(anime_id, |tags| {
+            let before = tags.len();
+            tags.retain(|tag| tag.name != name);
+            tags.len() != before
+        })
+        .await
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_animes_by_tag(&self, tag: &str) -> Result<Vec<AnimeState>> {
+        let _timer = self.timer("query_animes_by_tag");
+        let client = self.client().await?;
+        let needle = serde_json::json!([{ "name": tag }]);
+        let rows = client
+            .query(
+                "SELECT * FROM anime_state WHERE anime_item->'tags' @> $1 ORDER BY anime_id",
+                &[&needle],
+            )
+            .await?;
+        let ret = rows
+            .iter()
+            .map(AnimeState::try_from)
+            .collect::<Result<_>>()?;
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_animes_by_season(&self, year: i32, season: Season) -> Result<Vec<AnimeState>> {
+        let _timer = self.timer("query_animes_by_season");
+        let client = self.client().await?;
+        // narrow to well-formed dates in the year in SQL, then let
+        // AnimeItem::season decide the quarter
+        let rows = client
+            .query(
+                "SELECT * FROM anime_state \
+                 WHERE anime_item->>'date' ~ '^\\d{4}-\\d{2}-\\d{2}$' \
+                 AND anime_item->>'date' LIKE $1 \
+                 ORDER BY anime_item->>'date', anime_id",
+                &[&format!("{year:04}-%")],
+            )
+            .await?;
+        let animes = rows
+            .iter()
+            .map(AnimeState::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        let ret = animes
+            .into_iter()
+            .filter(|anime| anime.anime_item.season() == Some((year, season)))
+            .collect();
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn get_stats(&self) -> Result<Stats> {
+        let _timer = self.timer("get_stats");
+        let client = self.client().await?;
+        let totals = client
+            .query_one(
+                "SELECT COUNT(*),
+                    COUNT(*) FILTER (WHERE favorite),
+                    COALESCE(SUM(watched_count(watched_episodes)), 0)::bigint,
+                    AVG(rating)::float8
+                FROM anime_state",
+                &[],
+            )
+            .await?;
+        let total_watch_lists = client
+            .query_one("SELECT COUNT(*) FROM anime_list", &[])
+            .await?;
+        let by_status = client
+            .query(
+                "SELECT status, COUNT(*) FROM anime_state GROUP BY status",
+                &[],
+            )
+            .await?;
+
+        Ok(Stats {
+            total_animes: totals.get(0),
+            total_watch_lists: total_watch_lists.get(0),
+            favorites: totals.get(1),
+            by_status: by_status
+                .iter()
+                .map(|row| (row.get(0), row.get(1)))
+                .collect(),
+            episodes_watched: totals.get(2),
+            average_rating: totals.get(3),
+        })
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn random_anime(
+        &self,
+        status: Option<WatchStatus>,
+        unfinished: bool,
+    ) -> Result<AnimeState> {
+        let _timer = self.timer("random_anime");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT * FROM anime_state
+                WHERE ($1::text IS NULL OR status = $1)
+                AND (NOT $2 OR status <> 'Completed')
+                ORDER BY random() LIMIT 1",
+                &[&status.map(WatchStatus::as_str), &unfinished],
+            )
+            .await?;
+        let Some(row) = rows.first() else {
+            return Err(DbError::NoMatchingAnime);
+        };
+        row.try_into()
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_unfinished_animes(&self) -> Result<Vec<AnimeState>> {
+        let _timer = self.timer("query_unfinished_animes");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT * FROM anime_state WHERE status NOT IN ('Completed', 'Dropped')",
+                &[],
+            )
+            .await?;
+        let ret = rows
+            .iter()
+            .map(AnimeState::try_from)
+            .collect::<Result<_>>()?;
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_airing(&self, window_weeks: i64) -> Result<Vec<AnimeState>> {
+        let _timer = self.timer("query_airing");
+        let client = self.client().await?;
+        // a total of 0 means the episode count isn't known yet
+        let rows = client
+            .query(
+                "SELECT * FROM anime_state \
+                 WHERE (anime_item->>'total_episodes')::int = 0 \
+                 OR whole_watched_count(watched_episodes) < (anime_item->>'total_episodes')::int \
+                 ORDER BY anime_id",
+                &[],
+            )
+            .await?;
+        let today = Utc::now().date_naive();
+        let since = today - chrono::Duration::weeks(window_weeks);
+        let animes = rows
+            .iter()
+            .map(AnimeState::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        let ret = animes
+            .into_iter()
+            .filter(|anime| {
+                let Some(date) = anime.anime_item.air_date() else {
+                    debug!(
+                        "Skipping anime {} with unparseable date {:?}",
+                        anime.anime_id, anime.anime_item.date
+                    );
+                    return false;
+                };
+                (since..=today).contains(&date)
+            })
+            .collect();
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn query_backlog(&self) -> Result<Vec<BacklogItem>> {
+        let _timer = self.timer("query_backlog");
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT * FROM (
+                    SELECT *, (anime_item->>'total_episodes')::int
+                        - whole_watched_count(COALESCE(watched_episodes, '[]')) AS remaining
+                    FROM anime_state
+                ) backlog
+                WHERE remaining > 0
+                ORDER BY remaining DESC, anime_id",
+                &[],
+            )
+            .await?;
+        let ret = rows
+            .iter()
+            .map(|row| {
+                Ok(BacklogItem {
+                    anime_state: row.try_into()?,
+                    remaining: row.get("remaining"),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(ret)
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn year_in_review(&self, year: i32, episode_minutes: f64) -> Result<YearInReview> {
+        let _timer = self.timer("year_in_review");
+        let client = self.client().await?;
+        let rows = client.query("SELECT * FROM anime_state", &[]).await?;
+
+        let mut episodes_per_month = vec![0; 12];
+        let mut series_completed = 0;
+        let mut watched_in_year = Vec::new();
+        for row in &rows {
+            let state: AnimeState = row.try_into()?;
+            let times = state.watched_episodes.0.values().flatten();
+            let mut watched = false;
+            for at in times.clone().filter(|at| at.year() == year) {
+                episodes_per_month[at.month0() as usize] += 1;
+                watched = true;
+            }
+            if !watched {
+                continue;
+            }
+            // finished this year if every episode is in and the last one was
+            // watched this year
+            let total = usize::try_from(state.anime_item.total_episodes).unwrap_or_default();
+            if total > 0
+                && state.watched_episodes.whole_count() >= total
+                && times.max().is_some_and(|at| at.year() == year)
+            {
+                series_completed += 1;
+            }
+            watched_in_year.push(state);
+        }
+
+        let mut top_rated: Vec<_> = watched_in_year
+            .into_iter()
+            .filter(|state| state.rating.is_some())
+            .collect();
+        top_rated.sort_by(|a, b| {
+            let rating = |state: &AnimeState| state.rating.map_or(0.0, Float::value);
+            rating(b).total_cmp(&rating(a))
+        });
+        top_rated.truncate(YEAR_TOP_RATED);
+
+        let episodes_watched: i64 = episodes_per_month.iter().sum();
+        #[allow(clippy::cast_precision_loss)]
+        let hours_watched = episodes_watched as f64 * episode_minutes / 60.0;
+        Ok(YearInReview {
+            year,
+            episodes_watched,
+            episodes_per_month,
+            series_completed,
+            hours_watched,
+            top_rated,
+        })
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn export_all(&self) -> Result<LibraryExport> {
+        let _timer = self.timer("export_all");
+        let client = self.client().await?;
+        let watch_lists = client
+            .query("SELECT * FROM anime_list ORDER BY title", &[])
+            .await?;
+        let anime_states = client
+            .query("SELECT * FROM anime_state ORDER BY anime_id", &[])
+            .await?;
+        // both directions are exported, as stored
+        let relations = client
+            .query(
+                "SELECT anime_id, related_id, kind FROM relations ORDER BY anime_id, related_id",
+                &[],
+            )
+            .await?
+            .iter()
+            .filter_map(|row| {
+                let kind: &str = row.get(2);
+                match kind.parse() {
+                    Ok(kind) => Some(Relation {
+                        anime_id: row.get(0),
+                        related_id: row.get(1),
+                        kind,
+                    }),
+                    Err(e) => {
+                        let anime_id: i32 = row.get(0);
+                        warn!("Not exporting relation of anime {}: {}", anime_id, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        Ok(LibraryExport {
+            version: EXPORT_VERSION,
+            exported_at: Utc::now(),
+            watch_lists: watch_lists.iter().map(std::convert::Into::into).collect(),
+            anime_states: anime_states
+                .iter()
+                .map(AnimeState::try_from)
+                .collect::<Result<_>>()?,
+            relations,
+        })
+    }
+
+    #[instrument(level = "debug", skip_all)]
+    async fn import_all(&self, library: &LibraryExport, mode: ImportMode) -> Result<()> {
+        let _timer = self.timer("import_all");
+        let mut client = self.client().await?;
+        let transaction = client.transaction().await?;
+        if let ImportMode::Replace = mode {
+            // share tokens point at the old lists, so they go as well
+            transaction
+                .batch_execute("TRUNCATE anime_list, anime_state CASCADE")
+                .await?;
+        }
+
+        let stmt = transaction
+            .prepare(
+                "INSERT INTO anime_list (title, archived, animes) VALUES($1,$2,$3)
+                ON CONFLICT (title) DO UPDATE
+                SET archived = EXCLUDED.archived, animes = EXCLUDED.animes",
+            )
+            .await?;
+        for watch_list in &library.watch_lists {
+            transaction
+                .execute(
+                    &stmt,
+                    &[&watch_list.title, &watch_list.archived, &watch_list.animes],
+                )
+                .await?;
+        }
+
+        let stmt = transaction
+            .prepare(
+                "INSERT INTO anime_state
+                (anime_id, anime_item, favorite, watched_episodes, visible, rating, status,
+                personal_rank, note)
+                VALUES($1,$2,$3,$4,$5,$6,$7,$8,$9)
+                ON CONFLICT (anime_id) DO UPDATE SET
+                anime_item = EXCLUDED.anime_item,
+                favorite = EXCLUDED.favorite,
+                watched_episodes = EXCLUDED.watched_episodes,
+                visible = EXCLUDED.visible,
+                rating = EXCLUDED.rating,
+                status = EXCLUDED.status,
+                personal_rank = EXCLUDED.personal_rank,
+                note = EXCLUDED.note",
+            )
+            .await?;
+        for state in &library.anime_states {
+            let anime_item = serde_json::to_value(&state.anime_item).unwrap();
+            let watched_episodes = serde_json::to_value(&state.watched_episodes).unwrap();
+            transaction
+                .execute(
+                    &stmt,
+                    &[
+                        &state.anime_id,
+                        &anime_item,
+                        &state.favorite,
+                        &watched_episodes,
+                        &state.visibility,
+                        &state.rating.map(Float::value),
+                        &state.status.as_str(),
+                        &state.personal_rank,
+                        &state.note,
+                    ],
+                )
+                .await?;
+        }
+
+        // the inverse is written too, so a file listing only one direction
+        // still ends up linked both ways
+        let stmt = transaction
+            .prepare(
+                "INSERT INTO relations VALUES($1,$2,$3)
+                ON CONFLICT (anime_id, related_id) DO UPDATE SET kind = EXCLUDED.kind",
+            )
+            .await?;
+        for relation in &library.relations {
+            transaction
+                .execute(
+                    &stmt,
+                    &[
+                        &relation.anime_id,
+                        &relation.related_id,
+                        &relation.kind.as_str(),
+                    ],
+                )
+                .await?;
+            transaction
+                .execute(
+                    &stmt,
+                    &[
+                        &relation.related_id,
+                        &relation.anime_id,
+                        &relation.kind.inverse().as_str(),
+                    ],
+                )
+                .await?;
+        }
+
+        transaction.commit().await?;
+        self.anime_changed(LibraryEvent::import()).await;
         Ok(())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests;
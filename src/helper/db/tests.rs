@@ -0,0 +1,438 @@
+// End-to-end checks of the SQL against a real Postgres. Each test gets its own
+// database: a fresh one on the server in KSERVER_TEST_PG_URI when that is set,
+// otherwise a throwaway container started through testcontainers. With
+// neither available the tests print why and pass without running.
+
+use std::{sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use testcontainers_modules::{
+    postgres::Postgres,
+    testcontainers::{runners::AsyncRunner, ContainerAsync},
+};
+use tokio::sync::broadcast;
+use tokio_postgres::NoTls;
+
+use super::DbHelper;
+use crate::{
+    helper::{db_error::DbError, migrations::MIGRATION_LOCK_KEY, store::AnimeStore},
+    model::{
+        request::{ImportMode, SortOrder},
+        AnimeItem, Float, ImageSet, RelationKind, TokenScope, WatchStatus,
+    },
+    router::ComplexResponse,
+};
+
+pub(crate) struct TestDb {
+    pub(crate) db: Arc<DbHelper>,
+    config: tokio_postgres::Config,
+    // stops the container once the test is done with it
+    _container: Option<ContainerAsync<Postgres>>,
+}
+
+impl TestDb {
+    // A second, unpooled connection for holding a transaction open by hand
+    async fn raw_client(&self) -> tokio_postgres::Client {
+        let (client, connection) = self.config.connect(NoTls).await.unwrap();
+        tokio::spawn(connection);
+        client
+    }
+}
+
+pub(crate) async fn test_db() -> Option<TestDb> {
+    let (config, container) = if let Ok(uri) = std::env::var("KSERVER_TEST_PG_URI") {
+        (fresh_database(&uri).await, None)
+    } else {
+        match Postgres::default().start().await {
+            Ok(container) => {
+                let mut config = tokio_postgres::Config::new();
+                config
+                    .host(&container.get_host().await.unwrap().to_string())
+                    .port(container.get_host_port_ipv4(5432).await.unwrap())
+                    .user("postgres")
+                    .password("postgres")
+                    .dbname("postgres");
+                (config, Some(container))
+            }
+            Err(e) => {
+                eprintln!("Skipping database test: no KSERVER_TEST_PG_URI and no Docker ({e})");
+                return None;
+            }
+        }
+    };
+    let (events, _) = broadcast::channel(16);
+    Some(TestDb {
+        db: Arc::new(DbHelper::connect(config.clone(), events).await),
+        config,
+        _container: container,
+    })
+}
+
+// Left in place afterwards so a failing test's data can be inspected
+async fn fresh_database(uri: &str) -> tokio_postgres::Config {
+    let mut config: tokio_postgres::Config = uri.parse().unwrap();
+    let (client, connection) = config.connect(NoTls).await.unwrap();
+    tokio::spawn(connection);
+    let name = format!("kserver_test_{}", uuid::Uuid::new_v4().simple());
+    client
+        .batch_execute(&format!("CREATE DATABASE {name}"))
+        .await
+        .unwrap();
+    config.dbname(&name);
+    config
+}
+
+pub(crate) fn anime_item(id: i32) -> AnimeItem {
+    AnimeItem {
+        id,
+        name: format!("anime {id}"),
+        name_cn: String::new(),
+        summary: String::new(),
+        date: Some("2024-01-05".to_owned()),
+        eps: 12,
+        total_episodes: 12,
+        images: ImageSet {
+            large: String::new(),
+            common: String::new(),
+            medium: String::new(),
+            small: String::new(),
+        },
+        tags: None,
+        rating: None,
+    }
+}
+
+#[tokio::test]
+async fn anime_round_trip() {
+    let Some(test) = test_db().await else { return };
+    let db = &test.db;
+
+    db.insert_anime_item(anime_item(1)).await.unwrap();
+    db.add_new_watch_list("watching").await.unwrap();
+    db.add_item_to_watch_list(1, "watching").await.unwrap();
+    // adding twice must not duplicate the entry
+    db.add_item_to_watch_list(1, "watching").await.unwrap();
+    assert_eq!(db.get_watch_list("watching").await.unwrap().animes, vec![1]);
+
+    let state = db
+        .update_episodes_watched_state(1, &[Float::Int(1), Float::Int(2), Float::Half(2)], true)
+        .await
+        .unwrap();
+    assert_eq!(state.watched_episodes.0.len(), 3);
+    assert_eq!(state.watched_episodes.whole_count(), 2);
+
+    let state = db
+        .update_episode_watched_state(1, Float::Int(2), false)
+        .await
+        .unwrap();
+    assert_eq!(state.watched_episodes.whole_count(), 1);
+
+    let state = db.query_anime_by_id(1).await.unwrap();
+    assert_eq!(state.anime_item.name, "anime 1");
+    assert!(state.watched_episodes.0.contains_key(&Float::Half(2)));
+
+    db.delete_anime_state(1).await.unwrap();
+    assert!(matches!(
+        db.query_anime_by_id(1).await,
+        Err(DbError::AnimeNotFound(1))
+    ));
+}
+
+#[tokio::test]
+async fn removing_from_the_last_list_deletes_the_anime() {
+    let Some(test) = test_db().await else { return };
+    let db = &test.db;
+
+    db.insert_anime_item(anime_item(1)).await.unwrap();
+    db.add_new_watch_list("a").await.unwrap();
+    db.add_new_watch_list("b").await.unwrap();
+    db.add_item_to_watch_list(1, "a").await.unwrap();
+    db.add_item_to_watch_list(1, "b").await.unwrap();
+
+    // still referenced by b
+    db.delete_anime_state_from_watch_list(1, "a").await.unwrap();
+    assert!(db.get_watch_list("a").await.unwrap().animes.is_empty());
+    db.query_anime_by_id(1).await.unwrap();
+
+    db.delete_anime_state_from_watch_list(1, "b").await.unwrap();
+    assert!(matches!(
+        db.query_anime_by_id(1).await,
+        Err(DbError::AnimeNotFound(1))
+    ));
+}
+
+// A list edit that is in flight while the anime is removed from its other list
+// must be waited for; otherwise the removal sees no remaining list, deletes the
+// anime and the edit commits a reference to a row that no longer exists.
+#[tokio::test]
+async fn removal_waits_for_a_concurrent_add() {
+    let Some(test) = test_db().await else { return };
+    let db = test.db.clone();
+
+    db.insert_anime_item(anime_item(1)).await.unwrap();
+    db.add_new_watch_list("a").await.unwrap();
+    db.add_new_watch_list("b").await.unwrap();
+    db.add_item_to_watch_list(1, "a").await.unwrap();
+
+    let mut other = test.raw_client().await;
+    let adding = other.transaction().await.unwrap();
+    adding
+        .execute(
+            "UPDATE anime_list SET animes = array_append(animes, 1) WHERE title = 'b'",
+            &[],
+        )
+        .await
+        .unwrap();
+
+    let removal = tokio::spawn({
+        let db = db.clone();
+        async move { db.delete_anime_state_from_watch_list(1, "a").await }
+    });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(
+        !removal.is_finished(),
+        "removal did not wait for the open add"
+    );
+
+    adding.commit().await.unwrap();
+    removal.await.unwrap().unwrap();
+
+    assert_eq!(db.get_watch_list("b").await.unwrap().animes, vec![1]);
+    db.query_anime_by_id(1).await.unwrap();
+}
+
+#[tokio::test]
+async fn unknown_token_scope_is_read_only() {
+    let Some(test) = test_db().await else { return };
+
+    test.raw_client()
+        .await
+        .execute(
+            "INSERT INTO auth_tokens (token, scope) VALUES ('t', 'admin')",
+            &[],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        test.db.get_all_tokens().await.unwrap(),
+        vec![("t".to_owned(), TokenScope::Read)]
+    );
+}
+
+#[tokio::test]
+async fn relations_survive_export_and_import() {
+    let Some(source) = test_db().await else {
+        return;
+    };
+    let Some(target) = test_db().await else {
+        return;
+    };
+
+    source.db.insert_anime_item(anime_item(1)).await.unwrap();
+    source.db.insert_anime_item(anime_item(2)).await.unwrap();
+    source
+        .db
+        .add_relation(1, 2, RelationKind::Sequel)
+        .await
+        .unwrap();
+
+    let library = source.db.export_all().await.unwrap();
+    assert_eq!(library.relations.len(), 2);
+    target
+        .db
+        .import_all(&library, ImportMode::Replace)
+        .await
+        .unwrap();
+
+    let relations = target.db.query_relations(2).await.unwrap();
+    assert_eq!(relations.prequels.len(), 1);
+    assert_eq!(relations.prequels[0].anime_id, 1);
+}
+
+// The interleaving is forced by hand: the read starts, a write lands, and only
+// then does the read try to fill the cache
+#[tokio::test]
+async fn read_racing_a_write_does_not_fill_the_anime_cache() {
+    let Some(test) = test_db().await else { return };
+    let db = &test.db;
+    db.insert_anime_item(anime_item(1)).await.unwrap();
+    let stale = db.query_anime_by_id(1).await.unwrap();
+    db.animes.invalidate(&1).await;
+
+    let generation = db.cache_generation();
+    db.update_anime_favorite(1, true).await.unwrap();
+    db.fill_cache(&db.animes, 1, stale, generation).await;
+
+    assert!(db.animes.get(&1).await.is_none());
+    assert!(db.query_anime_by_id(1).await.unwrap().favorite);
+}
+
+#[tokio::test]
+async fn read_racing_a_write_does_not_fill_the_all_cache() {
+    let Some(test) = test_db().await else { return };
+    let db = &test.db;
+    let key = (10, 0, None, SortOrder::default());
+    let stale = db
+        .query_all_animes(10, 0, None, SortOrder::default())
+        .await
+        .unwrap();
+    db.all_animes.invalidate(&key).await;
+
+    let generation = db.cache_generation();
+    db.insert_anime_item(anime_item(1)).await.unwrap();
+    db.fill_cache(&db.all_animes, key, stale, generation).await;
+
+    assert!(db.all_animes.get(&key).await.is_none());
+    let animes = db
+        .query_all_animes(10, 0, None, SortOrder::default())
+        .await
+        .unwrap();
+    assert_eq!(animes.len(), 1);
+}
+
+#[tokio::test]
+async fn postgres_errors_are_not_sent_to_clients() {
+    let Some(test) = test_db().await else { return };
+
+    let error = test
+        .raw_client()
+        .await
+        .execute("SELECT secret_column FROM anime_state", &[])
+        .await
+        .unwrap_err();
+    let response = ComplexResponse::from(DbError::from(error));
+
+    assert_eq!(response.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(response.code, "PostgresError");
+    assert!(!response.message.contains("secret_column"));
+    assert!(response.detail.is_none());
+}
+
+// /completed lists the most recently finished first, which needs the status
+// change to count as an update. Without it both rows keep a NULL last_updated
+// and fall back to id order
+#[tokio::test]
+async fn completing_an_anime_moves_it_to_the_top_of_completed() {
+    let Some(test) = test_db().await else { return };
+    let db = &test.db;
+    db.insert_anime_item(anime_item(1)).await.unwrap();
+    db.insert_anime_item(anime_item(2)).await.unwrap();
+
+    db.update_anime_status(1, WatchStatus::Completed)
+        .await
+        .unwrap();
+    db.update_anime_status(2, WatchStatus::Completed)
+        .await
+        .unwrap();
+    let completed = db.query_completed().await.unwrap();
+    assert_eq!(
+        completed.iter().map(|s| s.anime_id).collect::<Vec<_>>(),
+        [2, 1]
+    );
+}
+
+#[tokio::test]
+async fn watch_list_visibility_covers_its_animes_only() {
+    let Some(test) = test_db().await else { return };
+    let db = &test.db;
+    db.insert_anime_item(anime_item(1)).await.unwrap();
+    db.insert_anime_item(anime_item(2)).await.unwrap();
+    db.add_new_watch_list("a").await.unwrap();
+    db.add_new_watch_list("b").await.unwrap();
+    db.add_item_to_watch_list(1, "a").await.unwrap();
+    db.add_item_to_watch_list(2, "b").await.unwrap();
+
+    db.update_watch_list_visibility("a", false).await.unwrap();
+    assert!(!db.query_anime_by_id(1).await.unwrap().visibility);
+    assert!(db.query_anime_by_id(2).await.unwrap().visibility);
+
+    assert!(matches!(
+        db.update_watch_list_visibility("missing", false).await,
+        Err(DbError::WatchListNotFound(name)) if name == "missing"
+    ));
+}
+
+#[tokio::test]
+async fn malformed_anime_item_is_an_error_not_a_panic() {
+    let Some(test) = test_db().await else { return };
+    let db = &test.db;
+    db.insert_anime_item(anime_item(1)).await.unwrap();
+    test.raw_client()
+        .await
+        .execute(
+            "UPDATE anime_state SET anime_item = '{\"id\": \"one\"}' WHERE anime_id = 1",
+            &[],
+        )
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        db.query_anime_by_id(1).await,
+        Err(DbError::MalformedAnimeItem(1))
+    ));
+    assert!(matches!(
+        db.update_episodes_watched_state(1, &[Float::Int(1)], true)
+            .await,
+        Err(DbError::MalformedAnimeItem(1))
+    ));
+    assert!(matches!(
+        db.add_tag(1, "drama").await,
+        Err(DbError::MalformedAnimeItem(1))
+    ));
+}
+
+#[tokio::test]
+async fn migrations_wait_for_the_advisory_lock() {
+    let Some(test) = test_db().await else { return };
+    let holder = test.raw_client().await;
+    let locked: bool = holder
+        .query_one("SELECT pg_try_advisory_lock($1)", &[&MIGRATION_LOCK_KEY])
+        .await
+        .unwrap()
+        .get(0);
+    assert!(locked, "the first startup left the migration lock held");
+
+    let config = test.config.clone();
+    let (events, _) = broadcast::channel(16);
+    let second = tokio::spawn(DbHelper::connect(config, events));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!second.is_finished());
+
+    holder
+        .execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK_KEY])
+        .await
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(10), second)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn shared_watch_list_hides_private_fields_and_hidden_animes() {
+    let Some(test) = test_db().await else { return };
+    let db = &test.db;
+
+    db.add_new_watch_list("shared").await.unwrap();
+    for id in [1, 2] {
+        db.insert_anime_item(anime_item(id)).await.unwrap();
+        db.add_item_to_watch_list(id, "shared").await.unwrap();
+        db.update_anime_note(id, Some("private")).await.unwrap();
+    }
+    db.reorder_ranking(&[1, 2]).await.unwrap();
+    db.update_anime_visibility(2, false).await.unwrap();
+    db.insert_share_token("share", "shared").await.unwrap();
+
+    let shared = db.get_shared_watch_list("share").await.unwrap();
+    assert_eq!(
+        shared.animes.iter().map(|a| a.anime_id).collect::<Vec<_>>(),
+        [1]
+    );
+    assert_eq!(shared.animes[0].note, None);
+    assert_eq!(shared.animes[0].personal_rank, None);
+
+    // the owner's own view is unchanged
+    let own = db.get_watch_list_expanded("shared").await.unwrap();
+    assert_eq!(own.animes.len(), 2);
+    assert_eq!(own.animes[0].note.as_deref(), Some("private"));
+}
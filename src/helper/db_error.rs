@@ -1,35 +1,120 @@
 use axum::http::StatusCode;
+use serde_json::json;
 use thiserror::Error;
 
 use crate::{router::ComplexResponse, status};
 
+use super::metrics;
+
 #[derive(Error, Debug)]
 pub enum DbError {
     #[error("Cannot find anime with id {0}")]
     AnimeNotFound(i32),
 
+    #[error("No anime matches the given filter")]
+    NoMatchingAnime,
+
+    #[error("Anime with id {0} already exists")]
+    AnimeExists(i32),
+
+    #[allow(dead_code)]
     #[error("Cannot find episode with id {0}")]
     EpisodeNotFound(i32),
 
+    #[error("Watched episodes of anime {0} are malformed")]
+    MalformedWatchedEpisodes(i32),
+
+    #[error("Stored item of anime {0} is malformed")]
+    MalformedAnimeItem(i32),
+
+    #[error("Anime {0} has {1} episodes, which cannot all be marked watched")]
+    EpisodeCountOutOfRange(i32, i32),
+
     #[error("Database error {0}")]
     PostgresError(#[from] tokio_postgres::Error),
 
     #[error("Cannot find watch list with id {0}")]
     WatchListNotFound(String),
+
+    #[error("Watch list {0} already exists")]
+    WatchListExists(String),
+
+    #[error("Anime {0} has no relation to anime {1}")]
+    RelationNotFound(i32, i32),
+
+    #[error("Share token {0} is not valid")]
+    ShareTokenNotFound(String),
+
+    #[error("Connection pool error {0}")]
+    PoolError(#[from] deadpool_postgres::PoolError),
+
+    #[error("Bangumi request error {0}")]
+    BangumiError(#[from] reqwest::Error),
+}
+
+impl DbError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DbError::AnimeNotFound(_) => "AnimeNotFound",
+            DbError::NoMatchingAnime => "NoMatchingAnime",
+            DbError::AnimeExists(_) => "AnimeExists",
+            DbError::EpisodeNotFound(_) => "EpisodeNotFound",
+            DbError::MalformedWatchedEpisodes(_) => "MalformedWatchedEpisodes",
+            DbError::MalformedAnimeItem(_) => "MalformedAnimeItem",
+            DbError::EpisodeCountOutOfRange(..) => "EpisodeCountOutOfRange",
+            DbError::PostgresError(_) => "PostgresError",
+            DbError::WatchListNotFound(_) => "WatchListNotFound",
+            DbError::WatchListExists(_) => "WatchListExists",
+            DbError::RelationNotFound(..) => "RelationNotFound",
+            DbError::ShareTokenNotFound(_) => "ShareTokenNotFound",
+            DbError::PoolError(_) => "PoolError",
+            DbError::BangumiError(_) => "BangumiError",
+        }
+    }
 }
 
 impl From<DbError> for ComplexResponse {
     fn from(value: DbError) -> Self {
         tracing::error!("Error: {:?}", value);
+        metrics::record_db_error(value.kind());
+        let code = value.kind();
+        let message = value.to_string();
         match value {
-            DbError::AnimeNotFound(id) => status!(NOT_FOUND, "Cannot find anime with id {}", id),
+            DbError::AnimeNotFound(id) => {
+                status!(NOT_FOUND, code, "{}", message).with_detail(json!({ "anime_id": id }))
+            }
+            DbError::NoMatchingAnime | DbError::ShareTokenNotFound(_) => {
+                status!(NOT_FOUND, code, "{}", message)
+            }
+            DbError::AnimeExists(id) => {
+                status!(CONFLICT, code, "{}", message).with_detail(json!({ "anime_id": id }))
+            }
             DbError::EpisodeNotFound(id) => {
-                status!(NOT_FOUND, "Cannot find episode with id {}", id)
+                status!(NOT_FOUND, code, "{}", message).with_detail(json!({ "episode": id }))
+            }
+            DbError::EpisodeCountOutOfRange(anime_id, total) => {
+                status!(BAD_REQUEST, code, "{}", message)
+                    .with_detail(json!({ "anime_id": anime_id, "total_episodes": total }))
+            }
+            DbError::MalformedWatchedEpisodes(id) | DbError::MalformedAnimeItem(id) => {
+                status!(INTERNAL_SERVER_ERROR, code, "{}", message)
+                    .with_detail(json!({ "anime_id": id }))
+            }
+            // Postgres messages name tables, columns and values, so they stay
+            // in the log above and clients only get the code
+            DbError::PostgresError(_) => {
+                status!(INTERNAL_SERVER_ERROR, code, "A database error occurred")
             }
-            DbError::PostgresError(e) => status!(INTERNAL_SERVER_ERROR, "Database error: {:?}", e),
-            DbError::WatchListNotFound(id) => {
-                status!(NOT_FOUND, "Cannot find watch list with id {}", id)
+            DbError::WatchListNotFound(name) => status!(NOT_FOUND, code, "{}", message)
+                .with_detail(json!({ "watch_list_name": name })),
+            DbError::WatchListExists(name) => status!(CONFLICT, code, "{}", message)
+                .with_detail(json!({ "watch_list_name": name })),
+            DbError::RelationNotFound(anime_id, related_id) => {
+                status!(NOT_FOUND, code, "{}", message)
+                    .with_detail(json!({ "anime_id": anime_id, "related_id": related_id }))
             }
+            DbError::PoolError(_) => status!(SERVICE_UNAVAILABLE, code, "{}", message),
+            DbError::BangumiError(_) => status!(BAD_GATEWAY, code, "{}", message),
         }
     }
 }
@@ -0,0 +1,105 @@
+use chrono::{Duration, NaiveDate, Utc};
+
+use crate::model::{AnimeState, MAX_EPISODE};
+
+pub struct AiringEpisode<'a> {
+    pub anime: &'a AnimeState,
+    pub ep: i32,
+    pub date: NaiveDate,
+}
+
+// Episodes are assumed to air weekly starting from the anime's first air date.
+// Counts come from Bangumi, so they're capped and date math is checked.
+pub fn upcoming_episodes(animes: &[AnimeState], today: NaiveDate) -> Vec<AiringEpisode<'_>> {
+    let mut episodes = vec![];
+    for anime in animes {
+        let Some(start) = anime.anime_item.air_date() else {
+            continue;
+        };
+        let total = match anime.anime_item.total_episodes {
+            0 => anime.anime_item.eps,
+            total => total,
+        }
+        .min(MAX_EPISODE);
+        // skip straight to the first episode airing on or after today
+        let elapsed_days = (today - start).num_days();
+        let first = if elapsed_days <= 0 {
+            1
+        } else {
+            i32::try_from((elapsed_days + 6) / 7 + 1).unwrap_or(i32::MAX)
+        };
+        for ep in first..=total {
+            let Some(date) = start.checked_add_signed(Duration::weeks(i64::from(ep - 1))) else {
+                break;
+            };
+            episodes.push(AiringEpisode { anime, ep, date });
+        }
+    }
+    episodes.sort_by_key(|e| e.date);
+    episodes
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+// RFC 5545 3.1: content lines are at most 75 octets, longer ones continue on
+// lines starting with a space. Folding happens between characters so a UTF-8
+// sequence is never split.
+const MAX_LINE_OCTETS: usize = 75;
+
+fn push_line(calendar: &mut String, line: &str) {
+    let mut limit = MAX_LINE_OCTETS;
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > limit {
+            calendar.push_str("\r\n ");
+            octets = 0;
+            // the leading space counts toward the continuation's limit
+            limit = MAX_LINE_OCTETS - 1;
+        }
+        calendar.push(c);
+        octets += c.len_utf8();
+    }
+    calendar.push_str("\r\n");
+}
+
+pub fn build_calendar(episodes: &[AiringEpisode]) -> String {
+    let stamp = Utc::now().format("%Y%m%dT%H%M%SZ");
+    let mut calendar = String::new();
+    push_line(&mut calendar, "BEGIN:VCALENDAR");
+    push_line(&mut calendar, "VERSION:2.0");
+    push_line(&mut calendar, "PRODID:-//KServer//Anime Calendar//EN");
+    push_line(&mut calendar, "X-WR-CALNAME:KServer");
+    for episode in episodes {
+        let item = &episode.anime.anime_item;
+        let name = if item.name_cn.is_empty() {
+            &item.name
+        } else {
+            &item.name_cn
+        };
+        push_line(&mut calendar, "BEGIN:VEVENT");
+        push_line(
+            &mut calendar,
+            &format!("UID:{}-{}@kserver", item.id, episode.ep),
+        );
+        push_line(&mut calendar, &format!("DTSTAMP:{stamp}"));
+        push_line(
+            &mut calendar,
+            &format!("DTSTART;VALUE=DATE:{}", episode.date.format("%Y%m%d")),
+        );
+        push_line(
+            &mut calendar,
+            &format!("SUMMARY:{} Ep {}", escape(name), episode.ep),
+        );
+        push_line(&mut calendar, "END:VEVENT");
+    }
+    push_line(&mut calendar, "END:VCALENDAR");
+    calendar
+}
+
+#[cfg(test)]
+mod tests;
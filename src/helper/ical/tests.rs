@@ -0,0 +1,103 @@
+use chrono::{Datelike, NaiveDate};
+
+use super::{build_calendar, push_line, upcoming_episodes, AiringEpisode, MAX_LINE_OCTETS};
+use crate::{
+    helper::db::tests::anime_item,
+    model::{AnimeState, WatchStatus, WatchedEpisodes, MAX_EPISODE},
+};
+
+#[test]
+fn short_lines_are_left_alone() {
+    let mut calendar = String::new();
+    push_line(&mut calendar, "VERSION:2.0");
+    assert_eq!(calendar, "VERSION:2.0\r\n");
+}
+
+#[test]
+fn long_lines_fold_without_splitting_characters() {
+    // 3 octets each, so a naive cut at 75 would land mid-character
+    let line = format!("SUMMARY:{}", "進撃の巨人".repeat(10));
+    let mut calendar = String::new();
+    push_line(&mut calendar, &line);
+
+    let physical = calendar
+        .trim_end_matches("\r\n")
+        .split("\r\n")
+        .collect::<Vec<_>>();
+    assert!(physical.len() > 1);
+    for (i, part) in physical.iter().enumerate() {
+        assert!(
+            part.len() <= MAX_LINE_OCTETS,
+            "line {i} is {} octets",
+            part.len()
+        );
+        assert_eq!(i > 0, part.starts_with(' '));
+    }
+    assert_eq!(calendar.replace("\r\n ", ""), format!("{line}\r\n"));
+}
+
+fn anime_state(item: crate::model::AnimeItem) -> AnimeState {
+    AnimeState {
+        anime_id: item.id,
+        anime_item: item,
+        favorite: false,
+        watched_episodes: WatchedEpisodes::default(),
+        visibility: true,
+        rating: None,
+        status: WatchStatus::Watching,
+        personal_rank: None,
+        note: None,
+    }
+}
+
+#[test]
+fn calendar_lines_stay_within_the_limit() {
+    let mut item = anime_item(1);
+    item.name_cn = "とある科学の超電磁砲".repeat(5);
+    let anime = anime_state(item);
+    let episodes = [AiringEpisode {
+        anime: &anime,
+        ep: 1,
+        date: NaiveDate::from_ymd_opt(2024, 1, 5).unwrap(),
+    }];
+
+    let calendar = build_calendar(&episodes);
+    assert!(calendar
+        .split("\r\n")
+        .all(|line| line.len() <= MAX_LINE_OCTETS));
+    assert!(calendar
+        .replace("\r\n ", "")
+        .contains(&anime.anime_item.name_cn));
+}
+
+#[test]
+fn upcoming_starts_at_the_first_episode_on_or_after_today() {
+    // twelve episodes, weekly from 2024-01-05
+    let animes = [anime_state(anime_item(1))];
+    let ymd = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+
+    let eps = |today| {
+        upcoming_episodes(&animes, today)
+            .iter()
+            .map(|e| (e.ep, e.date))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(eps(ymd(2024, 1, 1)).len(), 12);
+    assert_eq!(eps(ymd(2024, 1, 19))[0], (3, ymd(2024, 1, 19)));
+    assert_eq!(eps(ymd(2024, 1, 20))[0], (4, ymd(2024, 1, 26)));
+    assert!(eps(ymd(2024, 3, 23)).is_empty());
+}
+
+#[test]
+fn upcoming_survives_implausible_counts_and_dates() {
+    let mut huge = anime_item(1);
+    huge.total_episodes = i32::MAX;
+    let mut late = anime_item(2);
+    late.date = Some(format!("+{}-12-01", NaiveDate::MAX.year()));
+    let animes = [anime_state(huge), anime_state(late)];
+    assert!(animes[1].anime_item.air_date().is_some());
+
+    let today = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+    let episodes = upcoming_episodes(&animes, today);
+    assert!(episodes.len() <= 2 * MAX_EPISODE as usize);
+}
@@ -0,0 +1,51 @@
+use std::time::Instant;
+
+use axum::{extract::MatchedPath, http::Request, middleware::Next, response::Response};
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+const REQUEST_DURATION: &str = "http_request_duration_seconds";
+const UNMATCHED_PATH: &str = "<unmatched>";
+
+pub fn install() -> PrometheusHandle {
+    PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(REQUEST_DURATION.to_owned()),
+            &[
+                0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+            ],
+        )
+        .unwrap()
+        .install_recorder()
+        .unwrap()
+}
+
+pub async fn track_metrics<B>(request: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+    // Use the route template so ids in the path don't explode the label set;
+    // anything unrouted shares one label so scanners can't add new ones
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(UNMATCHED_PATH, MatchedPath::as_str)
+        .to_owned();
+    let method = request.method().to_string();
+
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    let labels = [("method", method), ("path", path), ("status", status)];
+    counter!("http_requests_total", &labels).increment(1);
+    histogram!(REQUEST_DURATION, &labels).record(start.elapsed().as_secs_f64());
+
+    response
+}
+
+pub fn record_db_error(kind: &'static str) {
+    counter!("db_errors_total", "kind" => kind).increment(1);
+}
+
+#[allow(clippy::cast_precision_loss)]
+pub fn set_active_tokens(count: usize) {
+    gauge!("auth_active_tokens").set(count as f64);
+}
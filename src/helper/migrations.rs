@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+
+use deadpool_postgres::Client;
+use tracing::info;
+
+// Applied in order and never edited once released; add a new file instead.
+// Every statement is idempotent so databases set up before migrations were
+// tracked can adopt them safely.
+const MIGRATIONS: &[(&str, &str)] = &[
+    (
+        "0001_initial",
+        include_str!("../../migrations/0001_initial.sql"),
+    ),
+    (
+        "0002_auth_tokens",
+        include_str!("../../migrations/0002_auth_tokens.sql"),
+    ),
+    (
+        "0003_watch_status",
+        include_str!("../../migrations/0003_watch_status.sql"),
+    ),
+    (
+        "0004_last_updated",
+        include_str!("../../migrations/0004_last_updated.sql"),
+    ),
+    (
+        "0005_half_ratings",
+        include_str!("../../migrations/0005_half_ratings.sql"),
+    ),
+    (
+        "0006_watched_count",
+        include_str!("../../migrations/0006_watched_count.sql"),
+    ),
+    (
+        "0007_share_tokens",
+        include_str!("../../migrations/0007_share_tokens.sql"),
+    ),
+    (
+        "0008_whole_watched_count",
+        include_str!("../../migrations/0008_whole_watched_count.sql"),
+    ),
+    (
+        "0009_personal_rank",
+        include_str!("../../migrations/0009_personal_rank.sql"),
+    ),
+    ("0010_note", include_str!("../../migrations/0010_note.sql")),
+    (
+        "0011_totp_secret",
+        include_str!("../../migrations/0011_totp_secret.sql"),
+    ),
+    (
+        "0012_token_scope",
+        include_str!("../../migrations/0012_token_scope.sql"),
+    ),
+    (
+        "0013_relations",
+        include_str!("../../migrations/0013_relations.sql"),
+    ),
+    (
+        "0014_totp_enrollment",
+        include_str!("../../migrations/0014_totp_enrollment.sql"),
+    ),
+];
+
+// Arbitrary key for pg_advisory_lock, shared by every instance pointed at the
+// same database so concurrent startups apply migrations one at a time
+pub(crate) const MIGRATION_LOCK_KEY: i64 = 0x006b_7365_7276_6572;
+
+pub async fn run(client: &mut Client) -> Result<(), tokio_postgres::Error> {
+    client
+        .execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK_KEY])
+        .await?;
+    let result = apply_pending(client).await;
+    // the lock is session scoped and pooled connections outlive this call
+    let unlocked = client
+        .execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK_KEY])
+        .await;
+    result.and(unlocked.map(|_| ()))
+}
+
+async fn apply_pending(client: &mut Client) -> Result<(), tokio_postgres::Error> {
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS _migrations (
+                name TEXT PRIMARY KEY,
+                applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .await?;
+    let applied: HashSet<String> = client
+        .query("SELECT name FROM _migrations", &[])
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+
+    for (name, sql) in MIGRATIONS {
+        if applied.contains(*name) {
+            continue;
+        }
+        let transaction = client.transaction().await?;
+        transaction.batch_execute(sql).await?;
+        transaction
+            .execute("INSERT INTO _migrations (name) VALUES($1)", &[name])
+            .await?;
+        transaction.commit().await?;
+        info!("Applied migration {}", name);
+    }
+    Ok(())
+}
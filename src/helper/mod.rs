@@ -1,2 +1,13 @@
-pub mod db_error;
+pub mod bangumi;
 pub mod db;
+pub mod db_error;
+pub mod ical;
+pub mod metrics;
+pub mod migrations;
+pub mod notify;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod store;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trace;
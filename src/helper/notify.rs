@@ -0,0 +1,49 @@
+use std::sync::OnceLock;
+
+use reqwest::Client;
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::model::AnimeItem;
+
+fn client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(Client::new)
+}
+
+// Fire and forget: the webhook runs on its own task so a slow or failing
+// Discord never holds up or fails the episode update
+pub fn notify_completion(anime: &AnimeItem) {
+    let Ok(webhook) = std::env::var("DISCORD_WEBHOOK_URL") else {
+        return;
+    };
+    let title = if anime.name_cn.is_empty() {
+        anime.name.clone()
+    } else {
+        anime.name_cn.clone()
+    };
+    let body = json!({
+        "embeds": [{
+            "title": format!("Finished {title}"),
+            "description": format!("All {} episodes watched", anime.total_episodes),
+            "url": format!("https://bgm.tv/subject/{}", anime.id),
+            "thumbnail": { "url": anime.images.common },
+        }]
+    });
+    let anime_id = anime.id;
+    tokio::spawn(async move {
+        let result = client()
+            .post(webhook)
+            .json(&body)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        match result {
+            Ok(_) => info!("Sent completion notification for anime {}", anime_id),
+            Err(e) => warn!(
+                "Completion notification for anime {} failed: {}",
+                anime_id, e
+            ),
+        }
+    });
+}
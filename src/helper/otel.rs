@@ -0,0 +1,35 @@
+use opentelemetry::{global, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use tracing::Subscriber;
+use tracing_subscriber::{registry::LookupSpan, Layer};
+
+use crate::startup_error;
+
+// The exporter picks up OTEL_EXPORTER_OTLP_ENDPOINT (and the other standard
+// OTEL_* variables) itself; we only look at it to decide whether to export
+pub fn layer<S>() -> Option<impl Layer<S>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .build()
+        .unwrap_or_else(|e| startup_error(&format!("Cannot create OTLP exporter: {e}")));
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new(
+            "service.name",
+            env!("CARGO_PKG_NAME"),
+        )]))
+        .build();
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    global::set_tracer_provider(provider);
+    Some(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Flushes spans still waiting in the batch exporter
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
@@ -0,0 +1,200 @@
+use async_trait::async_trait;
+
+use crate::{
+    model::{
+        request::{ImportMode, OnConflict, SortKey, SortOrder},
+        AnimeItem, AnimeRelations, AnimeState, BacklogItem, Float, LibraryExport, PoolStatus,
+        RelationKind, Season, Stats, Tag, TokenScope, WatchList, WatchListExpanded,
+        WatchListSummary, WatchStatus, YearInReview,
+    },
+    AuthToken,
+};
+
+use super::db_error::DbError;
+
+type Result<T> = std::result::Result<T, DbError>;
+
+/// Everything the handlers need from persistence. `DbHelper` is the Postgres
+/// implementation; handlers only see `Arc<dyn AnimeStore>`.
+#[cfg_attr(test, mockall::automock, allow(clippy::ref_option_ref))]
+#[async_trait]
+pub trait AnimeStore: Send + Sync {
+    async fn ping(&self) -> Result<()>;
+
+    fn pool_status(&self) -> PoolStatus;
+
+    async fn get_all_list(&self, archived: Option<bool>) -> Result<Vec<WatchList>>;
+
+    async fn get_all_list_summaries(&self, archived: Option<bool>)
+        -> Result<Vec<WatchListSummary>>;
+
+    async fn query_anime_by_id(&self, anime_id: i32) -> Result<AnimeState>;
+
+    async fn insert_anime_item(&self, anime_item: AnimeItem) -> Result<()>;
+
+    async fn insert_anime_items(&self, anime_items: &[AnimeItem]) -> Result<()>;
+
+    async fn insert_new_anime_item(&self, anime_item: AnimeItem) -> Result<()>;
+
+    async fn update_anime_item(&self, anime_id: i32, anime_item: AnimeItem) -> Result<()>;
+
+    async fn update_episode_watched_state(
+        &self,
+        anime_id: i32,
+        ep: Float,
+        watched: bool,
+    ) -> Result<AnimeState>;
+
+    async fn update_episodes_watched_state(
+        &self,
+        anime_id: i32,
+        eps: &[Float],
+        watched: bool,
+    ) -> Result<AnimeState>;
+
+    async fn mark_all_watched(&self, anime_id: i32) -> Result<AnimeState>;
+
+    async fn unmark_all(&self, anime_id: i32) -> Result<AnimeState>;
+
+    async fn add_item_to_watch_list(&self, anime_id: i32, watch_list_name: &str) -> Result<()>;
+
+    async fn move_anime_between_lists(&self, anime_id: i32, from: &str, to: &str) -> Result<()>;
+
+    async fn add_new_watch_list(&self, watch_list_name: &str) -> Result<()>;
+
+    async fn add_watch_lists(
+        &self,
+        watch_list_names: &[String],
+        on_conflict: OnConflict,
+    ) -> Result<Vec<String>>;
+
+    async fn rename_watch_list(&self, old_name: &str, new_name: &str) -> Result<()>;
+
+    async fn update_watch_list_archive_state(
+        &self,
+        watch_list_name: &str,
+        archived: bool,
+    ) -> Result<()>;
+
+    async fn update_anime_visibility(&self, anime_id: i32, visibility: bool) -> Result<AnimeState>;
+
+    async fn update_watch_list_visibility(
+        &self,
+        watch_list_name: &str,
+        visible: bool,
+    ) -> Result<()>;
+
+    async fn archive_and_hide(&self, watch_list_name: &str) -> Result<()>;
+
+    async fn update_anime_favorite(&self, anime_id: i32, favorite: bool) -> Result<AnimeState>;
+
+    async fn update_anime_note<'a>(
+        &self,
+        anime_id: i32,
+        note: Option<&'a str>,
+    ) -> Result<AnimeState>;
+
+    async fn query_favorite_animes(&self) -> Result<Vec<AnimeState>>;
+
+    async fn query_completed(&self) -> Result<Vec<AnimeState>>;
+
+    async fn update_anime_status(&self, anime_id: i32, status: WatchStatus) -> Result<AnimeState>;
+
+    async fn query_animes_by_status(&self, status: WatchStatus) -> Result<Vec<AnimeState>>;
+
+    async fn delete_watch_list(&self, watch_list_name: &str) -> Result<()>;
+
+    async fn query_anime_states_by_ids(&self, anime_ids: &[i32]) -> Result<Vec<AnimeState>>;
+
+    async fn delete_anime_state_from_watch_list(
+        &self,
+        anime_id: i32,
+        watch_list_name: &str,
+    ) -> Result<()>;
+
+    async fn delete_anime_state(&self, anime_id: i32) -> Result<()>;
+
+    async fn query_continue_watching(&self, limit: i64) -> Result<Vec<AnimeState>>;
+
+    async fn query_all_animes(
+        &self,
+        limit: i64,
+        offset: i64,
+        sort: Option<SortKey>,
+        order: SortOrder,
+    ) -> Result<Vec<AnimeState>>;
+
+    async fn reorder_ranking(&self, anime_ids: &[i32]) -> Result<()>;
+
+    async fn query_ranked(&self) -> Result<Vec<AnimeState>>;
+
+    async fn query_top_rated(&self, limit: i64, order: SortOrder) -> Result<Vec<AnimeState>>;
+
+    async fn count_all_animes(&self) -> Result<i64>;
+
+    async fn get_watch_list(&self, watch_list_name: &str) -> Result<WatchList>;
+
+    async fn get_watch_list_expanded(&self, watch_list_name: &str) -> Result<WatchListExpanded>;
+
+    async fn update_anime_rating(&self, anime_id: i32, rating: Float) -> Result<AnimeState>;
+
+    async fn get_all_tokens(&self) -> Result<Vec<(AuthToken, TokenScope)>>;
+
+    async fn insert_token(&self, token: &str, scope: TokenScope) -> Result<()>;
+
+    async fn delete_token(&self, token: &str) -> Result<()>;
+
+    async fn delete_all_tokens(&self) -> Result<()>;
+
+    async fn get_totp_secret(&self) -> Result<Option<String>>;
+
+    async fn set_totp_secret(&self, secret: &str) -> Result<()>;
+
+    async fn is_totp_enrolled(&self) -> Result<bool>;
+
+    async fn set_totp_enrolled(&self) -> Result<()>;
+
+    async fn insert_share_token(&self, token: &str, watch_list_name: &str) -> Result<()>;
+
+    async fn get_shared_watch_list(&self, token: &str) -> Result<WatchListExpanded>;
+
+    async fn delete_share_token(&self, token: &str) -> Result<()>;
+
+    async fn search_anime_by_name(&self, query: &str, limit: i64) -> Result<Vec<AnimeState>>;
+
+    async fn get_all_tags(&self) -> Result<Vec<Tag>>;
+
+    async fn add_relation(&self, anime_id: i32, related_id: i32, kind: RelationKind) -> Result<()>;
+
+    async fn delete_relation(&self, anime_id: i32, related_id: i32) -> Result<()>;
+
+    async fn query_relations(&self, anime_id: i32) -> Result<AnimeRelations>;
+
+    async fn add_tag(&self, anime_id: i32, name: &str) -> Result<()>;
+
+    async fn remove_tag(&self, anime_id: i32, name: &str) -> Result<()>;
+
+    async fn query_animes_by_tag(&self, tag: &str) -> Result<Vec<AnimeState>>;
+
+    async fn query_animes_by_season(&self, year: i32, season: Season) -> Result<Vec<AnimeState>>;
+
+    async fn get_stats(&self) -> Result<Stats>;
+
+    async fn random_anime(
+        &self,
+        status: Option<WatchStatus>,
+        unfinished: bool,
+    ) -> Result<AnimeState>;
+
+    async fn query_unfinished_animes(&self) -> Result<Vec<AnimeState>>;
+
+    async fn query_airing(&self, window_weeks: i64) -> Result<Vec<AnimeState>>;
+
+    async fn query_backlog(&self) -> Result<Vec<BacklogItem>>;
+
+    async fn year_in_review(&self, year: i32, episode_minutes: f64) -> Result<YearInReview>;
+
+    async fn export_all(&self) -> Result<LibraryExport>;
+
+    async fn import_all(&self, library: &LibraryExport, mode: ImportMode) -> Result<()>;
+}
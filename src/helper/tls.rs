@@ -0,0 +1,34 @@
+use std::{fs::File, io::BufReader, sync::Arc};
+
+use rustls::{ClientConfig, RootCertStore};
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tracing::info;
+
+use crate::startup_error;
+
+// Trust the CA bundle in PG_SSLROOTCERT if given, otherwise the bundled webpki roots
+pub fn make_connector() -> MakeRustlsConnect {
+    let mut roots = RootCertStore::empty();
+    if let Ok(path) = std::env::var("PG_SSLROOTCERT") {
+        info!("Loading Postgres CA certificates from {}", path);
+        let file = File::open(&path)
+            .unwrap_or_else(|e| startup_error(&format!("Cannot open PG_SSLROOTCERT {path}: {e}")));
+        for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+            let cert = cert
+                .unwrap_or_else(|e| startup_error(&format!("Invalid certificate in {path}: {e}")));
+            roots
+                .add(cert)
+                .unwrap_or_else(|e| startup_error(&format!("Rejected certificate in {path}: {e}")));
+        }
+    } else {
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    }
+
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap_or_else(|e| startup_error(&format!("Cannot configure TLS: {e}")))
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    MakeRustlsConnect::new(config)
+}
@@ -0,0 +1,45 @@
+use axum::{
+    extract::MatchedPath,
+    http::{HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{field, info, info_span, Instrument};
+use uuid::Uuid;
+
+static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Everything logged while handling the request is nested in this span, so
+// lines can be grouped per request in the log file
+pub async fn trace_request<B>(request: Request<B>, next: Next<B>) -> Response {
+    let path = request.extensions().get::<MatchedPath>().map_or_else(
+        || request.uri().path().to_owned(),
+        |path| path.as_str().to_owned(),
+    );
+    // keep an id handed in by a proxy so its logs line up with ours
+    let request_id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .cloned()
+        .unwrap_or_else(|| HeaderValue::from_str(&Uuid::new_v4().to_string()).unwrap());
+    let span = info_span!(
+        "request",
+        request_id = request_id.to_str().unwrap_or_default(),
+        method = %request.method(),
+        path,
+        status = field::Empty,
+    );
+
+    async move {
+        let mut response = next.run(request).await;
+        let status = response.status().as_u16();
+        tracing::Span::current().record("status", status);
+        info!("Request completed");
+        response
+            .headers_mut()
+            .insert(REQUEST_ID.clone(), request_id);
+        response
+    }
+    .instrument(span)
+    .await
+}
@@ -1,20 +1,43 @@
-use core::panic;
-use std::{io::Write, sync::Arc, time::SystemTimeError};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTimeError},
+};
 
 use axum::{
-    body::BoxBody,
     extract::State,
-    http::{Method, Request, StatusCode},
-    middleware::Next,
+    http::{header, HeaderName, HeaderValue, Method, Request, StatusCode},
+    middleware::{from_fn, from_fn_with_state, Next},
     response::{IntoResponse, Response},
     Router,
 };
-use helper::db::DbHelper;
-use rand::Rng;
-use tokio::sync::Mutex;
-use totp_rs::{Algorithm, TOTP};
-use tower_http::cors::{Any, CorsLayer};
+use clap::{Args, Parser, Subcommand};
+use helper::{db::DbHelper, db_error::DbError, metrics, store::AnimeStore, trace};
+use metrics_exporter_prometheus::PrometheusHandle;
+use model::{LibraryEvent, TokenScope};
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tokio_util::sync::CancellationToken;
+use totp_rs::{Algorithm, TotpUrlError, TOTP};
+use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, NotForContentType, Predicate},
+        CompressionLayer,
+    },
+    cors::{AllowOrigin, Any, CorsLayer},
+};
 use tracing::{event, Level};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt, Layer,
+};
 
 mod helper;
 mod model;
@@ -22,6 +45,12 @@ mod router;
 
 pub type AuthToken = String;
 
+const API_PREFIX: &str = "/v1";
+// subscribers further behind than this skip ahead instead of buffering
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+const DEFAULT_MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+const DEFAULT_MAX_IMPORT_BODY_BYTES: usize = 64 * 1024 * 1024;
+
 fn gen_token() -> AuthToken {
     let mut rng = rand::thread_rng();
     let mut token = vec![];
@@ -33,13 +62,28 @@ fn gen_token() -> AuthToken {
 
 #[derive(Clone)]
 struct AppState {
-    pub db_helper: DbHelper,
-    totp: TOTP,
-    token: Arc<Mutex<Vec<AuthToken>>>,
+    pub db_helper: Arc<dyn AnimeStore>,
+    totp: Arc<RwLock<TOTP>>,
+    totp_enrolled: Arc<AtomicBool>,
+    token: Arc<Mutex<Vec<(AuthToken, TokenScope)>>>,
+    login_attempts: Arc<Mutex<HashMap<IpAddr, (u32, Instant)>>>,
+    login_limit: u32,
+    login_window: Duration,
+    trusted_proxies: Arc<[IpAddr]>,
+    pub search_limit: i64,
+    pub max_body_bytes: usize,
+    pub max_import_body_bytes: usize,
+    pub episode_minutes: f64,
+    pub metrics_handle: PrometheusHandle,
+    pub feed_token: Option<String>,
+    pub events: broadcast::Sender<LibraryEvent>,
+    // cancelled once shutdown starts, so long-lived streams end and let the
+    // graceful shutdown finish
+    pub shutdown: CancellationToken,
 }
 
 pub enum AuthStatus {
-    Authenticated,
+    Authenticated(TokenScope),
     AuthNotValid,
     AuthExpired,
     NotLoggedIn,
@@ -48,29 +92,164 @@ pub enum AuthStatus {
 impl AppState {
     pub async fn new() -> Self {
         event!(Level::INFO, "Start creating app state...");
-        event!(Level::INFO, "Creating TOTP...");
-
-        let totp = init_totp();
+        let metrics_handle = metrics::install();
 
         event!(Level::INFO, "Creating database helper...");
-        let db_helper = DbHelper::new().await;
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let db_helper: Arc<dyn AnimeStore> = Arc::new(DbHelper::new(events.clone()).await);
         event!(Level::INFO, "Database helper created");
 
-        let token = Arc::new(Mutex::new(vec![]));
+        event!(Level::INFO, "Creating TOTP...");
+        let totp = init_totp(
+            db_helper
+                .get_totp_secret()
+                .await
+                .unwrap_or_else(|e| startup_error(&format!("Cannot load the TOTP secret: {e}"))),
+        );
+        let totp = Arc::new(RwLock::new(totp));
+        let totp_enrolled = db_helper.is_totp_enrolled().await.unwrap_or_else(|e| {
+            startup_error(&format!("Cannot load the TOTP enrollment state: {e}"))
+        });
+        if !totp_enrolled {
+            event!(Level::WARN, "TOTP enrollment is open until the first login");
+        }
+        let totp_enrolled = Arc::new(AtomicBool::new(totp_enrolled));
+
+        event!(Level::INFO, "Loading persisted tokens...");
+        let token = db_helper
+            .get_all_tokens()
+            .await
+            .unwrap_or_else(|e| startup_error(&format!("Cannot load persisted tokens: {e}")));
+        metrics::set_active_tokens(token.len());
+        let token = Arc::new(Mutex::new(token));
+
+        let login_limit = std::env::var("KSERVER_LOGIN_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(5);
+        let login_window = Duration::from_secs(
+            std::env::var("KSERVER_LOGIN_WINDOW_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(60),
+        );
+        event!(
+            Level::INFO,
+            "Login rate limit: {} attempts per {:?}",
+            login_limit,
+            login_window
+        );
+        let trusted_proxies = trusted_proxies().into();
+
+        let search_limit = std::env::var("KSERVER_SEARCH_LIMIT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(50);
+
+        let max_body_bytes = std::env::var("KSERVER_MAX_BODY_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_BODY_BYTES);
+        let max_import_body_bytes = std::env::var("KSERVER_MAX_IMPORT_BODY_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_IMPORT_BODY_BYTES);
+        event!(
+            Level::INFO,
+            "Body limit: {} bytes, {} bytes for imports",
+            max_body_bytes,
+            max_import_body_bytes
+        );
+
+        // used to estimate hours watched from episode counts
+        let episode_minutes = std::env::var("KSERVER_EPISODE_MINUTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(24.0);
 
         Self {
             db_helper,
             totp,
+            totp_enrolled,
             token,
+            login_attempts: Arc::new(Mutex::new(HashMap::new())),
+            login_limit,
+            login_window,
+            trusted_proxies,
+            search_limit,
+            max_body_bytes,
+            max_import_body_bytes,
+            episode_minutes,
+            metrics_handle,
+            feed_token: std::env::var("KSERVER_FEED_TOKEN").ok(),
+            events,
+            shutdown: CancellationToken::new(),
         }
     }
 
-    pub fn verify(&self, code: &str) -> Result<bool, SystemTimeError> {
+    // Checks the limit and counts the attempt under one lock, before the code
+    // is verified, so parallel guesses from one address can't all slip past
+    // the check. A successful login resets the count again.
+    pub async fn reserve_login_attempt(&self, ip: IpAddr) -> bool {
+        let mut attempts = self.login_attempts.lock().await;
+        // windows that have run out are dropped here, the only place entries
+        // are added, so the map only holds addresses seen in the last window
+        attempts.retain(|_, (_, window_start)| window_start.elapsed() < self.login_window);
+        let entry = attempts.entry(ip).or_insert((0, Instant::now()));
+        if entry.0 >= self.login_limit {
+            return false;
+        }
+        entry.0 += 1;
+        event!(Level::INFO, "Login attempt from {}, count: {}", ip, entry.0);
+        true
+    }
+
+    pub async fn reset_login_attempts(&self, ip: IpAddr) {
+        let mut attempts = self.login_attempts.lock().await;
+        attempts.remove(&ip);
+    }
+
+    pub async fn verify(&self, code: &str) -> Result<bool, SystemTimeError> {
         // if MOCK_TOTP is set, return true
         if std::env::var("MOCK_TOTP").is_ok() {
             return Ok(true);
         }
-        self.totp.check_current(code)
+        self.totp.read().await.check_current(code)
+    }
+
+    pub fn is_totp_enrolled(&self) -> bool {
+        self.totp_enrolled.load(Ordering::Acquire)
+    }
+
+    // Called on every successful login, the first one proves an authenticator
+    // holds the secret and closes enrollment for good
+    pub async fn finish_totp_enrollment(&self) -> Result<(), DbError> {
+        if self.is_totp_enrolled() {
+            return Ok(());
+        }
+        self.db_helper.set_totp_enrolled().await?;
+        self.totp_enrolled.store(true, Ordering::Release);
+        event!(Level::INFO, "TOTP enrollment closed");
+        Ok(())
+    }
+
+    pub async fn totp_qr_png(&self) -> Result<Vec<u8>, String> {
+        self.totp.read().await.get_qr_png()
+    }
+
+    // The write lock is held while the new secret is persisted, so a login
+    // verifies against either the old or the new secret, never a mix, and two
+    // rotations can't leave the database and memory disagreeing. A code read
+    // off the old secret that arrives after the swap is rejected, even inside
+    // the skew window. Issued tokens don't depend on the secret and stay valid.
+    pub async fn rotate_totp(&self) -> Result<TOTP, DbError> {
+        let secret = gen_secret();
+        let totp = build_totp(secret.clone()).expect("generated secret is long enough");
+        let mut current = self.totp.write().await;
+        self.db_helper.set_totp_secret(&secret).await?;
+        *current = totp.clone();
+        event!(Level::INFO, "TOTP secret rotated");
+        Ok(totp)
     }
 
     pub async fn auth(&self, in_token: &str) -> AuthStatus {
@@ -80,154 +259,632 @@ impl AppState {
         event!(Level::INFO, "Token list: {:?}", token);
 
         // check if in_token is in token list
-        let mut found = false;
-        for i in 0..token.len() {
-            if token[i] == in_token {
-                found = true;
-                break;
-            }
-        }
-        if !found {
+        let Some((_, scope)) = token.iter().find(|(t, _)| t == in_token) else {
             return AuthStatus::NotLoggedIn;
-        }
+        };
         event!(Level::INFO, "Token found");
-        AuthStatus::Authenticated
+        AuthStatus::Authenticated(*scope)
     }
 
-    pub async fn gen_token(&self) -> String {
+    pub async fn gen_token(&self, scope: TokenScope) -> Result<String, DbError> {
         let mut token = self.token.lock().await;
         let auth_token = gen_token();
-        token.push(auth_token.clone());
-        event!(Level::INFO, "Token generated: {}", auth_token);
-        auth_token
+        self.db_helper.insert_token(&auth_token, scope).await?;
+        token.push((auth_token.clone(), scope));
+        metrics::set_active_tokens(token.len());
+        event!(
+            Level::INFO,
+            "Token generated: {} ({})",
+            auth_token,
+            scope.as_str()
+        );
+        Ok(auth_token)
     }
 
-    pub async fn clear_token(&self, in_token: &str) {
+    pub async fn clear_token(&self, in_token: &str) -> Result<(), DbError> {
         let mut token = self.token.lock().await;
-        let mut found = false;
-        for i in 0..token.len() {
-            if token[i] == in_token {
-                found = true;
-                break;
-            }
-        }
-        if !found {
-            return;
+        let Some(index) = token.iter().position(|(t, _)| t == in_token) else {
+            return Ok(());
+        };
+        self.db_helper.delete_token(in_token).await?;
+        token.swap_remove(index);
+        metrics::set_active_tokens(token.len());
+        Ok(())
+    }
+
+    pub async fn clear_all_tokens(&self) -> Result<(), DbError> {
+        let mut token = self.token.lock().await;
+        self.db_helper.delete_all_tokens().await?;
+        token.clear();
+        metrics::set_active_tokens(0);
+        event!(Level::INFO, "All tokens revoked");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    // Skips the env lookups and the global metrics recorder so tests can build
+    // as many states as they like around a mock store
+    pub fn for_tests(db_helper: Arc<dyn AnimeStore>) -> Self {
+        let totp = TOTP::new(
+            Algorithm::SHA256,
+            8,
+            1,
+            30,
+            b"kserver-test-secret".to_vec(),
+            None,
+            "test".to_owned(),
+        )
+        .unwrap();
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self {
+            db_helper,
+            totp: Arc::new(RwLock::new(totp)),
+            totp_enrolled: Arc::new(AtomicBool::new(false)),
+            token: Arc::new(Mutex::new(Vec::new())),
+            login_attempts: Arc::new(Mutex::new(HashMap::new())),
+            login_limit: 5,
+            login_window: Duration::from_mins(1),
+            trusted_proxies: Arc::new([]),
+            search_limit: 50,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            max_import_body_bytes: DEFAULT_MAX_IMPORT_BODY_BYTES,
+            episode_minutes: 24.0,
+            metrics_handle: metrics_exporter_prometheus::PrometheusBuilder::new()
+                .build_recorder()
+                .handle(),
+            feed_token: None,
+            events,
+            shutdown: CancellationToken::new(),
         }
-        token.swap_remove(usize::from(found));
+    }
+
+    pub async fn with_token(self, token: &str, scope: TokenScope) -> Self {
+        self.token.lock().await.push((token.to_owned(), scope));
+        self
     }
 }
 
-fn init_totp() -> TOTP {
-    let totp = TOTP::new(
-        Algorithm::SHA256,
-        8,
-        1,
-        30,
-        std::env::var("KSERVER_SECRET").unwrap().into_bytes(),
-        Some("KServer".to_owned()),
-        "SmilingPie".to_owned(),
-    );
-    if let Err(e) = totp {
-        panic!("Error: {:?}", e);
+// totp-rs rejects secrets shorter than 128 bits
+const MIN_SECRET_LENGTH: usize = 16;
+const ROTATED_SECRET_LENGTH: usize = 32;
+
+pub fn startup_error(message: &str) -> ! {
+    event!(Level::ERROR, "{}", message);
+    eprintln!("{message}");
+    std::process::exit(1);
+}
+
+fn totp_env<T: FromStr>(name: &str, default: T) -> T
+where
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|e| startup_error(&format!("Invalid {name} {value:?}: {e}"))),
+        Err(_) => default,
     }
-    let totp = totp.unwrap();
+}
+
+fn totp_algorithm() -> Algorithm {
+    let Ok(algorithm) = std::env::var("KSERVER_TOTP_ALGORITHM") else {
+        return Algorithm::SHA256;
+    };
+    match algorithm.to_uppercase().as_str() {
+        "SHA1" => Algorithm::SHA1,
+        "SHA256" => Algorithm::SHA256,
+        "SHA512" => Algorithm::SHA512,
+        _ => startup_error(&format!(
+            "Invalid KSERVER_TOTP_ALGORITHM {algorithm:?}: expected SHA1, SHA256 or SHA512"
+        )),
+    }
+}
+
+fn gen_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(ROTATED_SECRET_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+// A secret persisted by a rotation wins over KSERVER_SECRET
+fn init_totp(persisted: Option<String>) -> TOTP {
+    let secret = if let Some(secret) = persisted {
+        event!(Level::INFO, "Using rotated TOTP secret from the database");
+        secret
+    } else {
+        let Ok(secret) = std::env::var("KSERVER_SECRET") else {
+            startup_error("KSERVER_SECRET must be set");
+        };
+        if secret.len() < MIN_SECRET_LENGTH {
+            startup_error(&format!(
+                "KSERVER_SECRET must be at least {MIN_SECRET_LENGTH} bytes, got {}",
+                secret.len()
+            ));
+        }
+        secret
+    };
+    let totp = build_totp(secret)
+        .unwrap_or_else(|e| startup_error(&format!("Invalid TOTP configuration: {e}")));
     event!(Level::INFO, "TOTP created");
-    event!(
-        Level::INFO,
-        "totp secret: {}",
-        String::from_utf8(totp.secret.clone()).unwrap()
-    );
     totp
 }
 
+fn build_totp(secret: String) -> Result<TOTP, TotpUrlError> {
+    let algorithm = totp_algorithm();
+    let digits = totp_env("KSERVER_TOTP_DIGITS", 8);
+    if !(6..=8).contains(&digits) {
+        startup_error(&format!(
+            "KSERVER_TOTP_DIGITS must be between 6 and 8, got {digits}"
+        ));
+    }
+    let period = totp_env("KSERVER_TOTP_PERIOD", 30);
+    if period == 0 {
+        startup_error("KSERVER_TOTP_PERIOD must be greater than 0");
+    }
+    // check_current accepts this many steps either side of the current one
+    let skew = totp_env("KSERVER_TOTP_SKEW", 1);
+    TOTP::new(
+        algorithm,
+        digits,
+        skew,
+        period,
+        secret.into_bytes(),
+        Some("KServer".to_owned()),
+        "SmilingPie".to_owned(),
+    )
+}
+
+// Writes are never GET, so the method alone decides
+fn is_read_request<B>(request: &Request<B>) -> bool {
+    matches!(*request.method(), Method::GET | Method::HEAD)
+}
+
 async fn auth_middleware<B>(
     State(app_state): State<AppState>,
     request: Request<B>,
     next: Next<B>,
+) -> Response {
+    let is_read = is_read_request(&request);
+    authenticate(&app_state, request, next, is_read).await
+}
+
+// Lets read-only tokens through whatever the method. Only for routes that
+// don't write, such as POST /validate where a read-only client checks its token
+async fn read_auth_middleware<B>(
+    State(app_state): State<AppState>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    authenticate(&app_state, request, next, true).await
+}
+
+async fn authenticate<B>(
+    app_state: &AppState,
+    request: Request<B>,
+    next: Next<B>,
+    is_read: bool,
 ) -> Response {
     let token = request.headers().get("Authorization");
     if token.is_none() {
-        return Response::builder()
-            .status(401)
-            .body(BoxBody::default())
-            .unwrap();
+        return status!(UNAUTHORIZED, "NotLoggedIn", "Missing Authorization header")
+            .into_response();
     }
     let token = token.unwrap().to_str().unwrap();
     let token = token.split(' ').collect::<Vec<&str>>();
     if token.len() != 2 {
-        return Response::builder()
-            .status(401)
-            .body(BoxBody::default())
-            .unwrap();
+        return status!(
+            UNAUTHORIZED,
+            "AuthNotValid",
+            "Malformed Authorization header"
+        )
+        .into_response();
     }
     let token = token[1];
     let ret = app_state.auth(token).await;
 
     match ret {
-        AuthStatus::Authenticated => {
+        AuthStatus::Authenticated(TokenScope::Read) if !is_read => {
+            event!(Level::INFO, "Read-only token used for {}", request.method());
+            status!(FORBIDDEN, "ReadOnlyToken", "Token only grants read access").into_response()
+        }
+        AuthStatus::Authenticated(_) => {
             event!(Level::INFO, "Authenticated");
             next.run(request).await
         }
         AuthStatus::AuthNotValid => {
             event!(Level::INFO, "Auth not valid");
-            status!(UNAUTHORIZED, "AuthNotValid").into_response()
+            status!(UNAUTHORIZED, "AuthNotValid", "Token is not valid").into_response()
         }
         AuthStatus::AuthExpired => {
             event!(Level::INFO, "Auth expired");
-            status!(UNAUTHORIZED, "AuthExpired").into_response()
+            status!(UNAUTHORIZED, "AuthExpired", "Token has expired").into_response()
         }
         AuthStatus::NotLoggedIn => {
             event!(Level::INFO, "Not logged in");
-            status!(UNAUTHORIZED, "NotLoggedIn").into_response()
+            status!(UNAUTHORIZED, "NotLoggedIn", "Token is not logged in").into_response()
         }
     }
 }
 
-#[tokio::main]
-async fn main() {
+async fn timeout_middleware<B>(
+    State(timeout): State<Duration>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = request.uri().path().to_owned();
+    if let Ok(response) = tokio::time::timeout(timeout, next.run(request)).await {
+        response
+    } else {
+        event!(
+            Level::WARN,
+            "Request to {} timed out after {:?}",
+            path,
+            timeout
+        );
+        status!(GATEWAY_TIMEOUT, "RequestTimeout", "Request timed out").into_response()
+    }
+}
 
-    if std::env::var("GENERATE_TOTP_QR").is_ok() {
-        let totp = init_totp();
-        std::fs::remove_file("./qr.png").unwrap_or_default();
-            let qr = totp.get_qr_png().unwrap();
-            let mut file = std::fs::File::create("./qr.png").unwrap();
-            file.write_all(&qr).unwrap();
-        return;
+async fn deprecation_middleware<B>(request: Request<B>, next: Next<B>) -> Response {
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .insert("Deprecation", HeaderValue::from_static("true"));
+    response
+}
+
+fn bind_addr() -> SocketAddr {
+    let bind = std::env::var("KSERVER_BIND").unwrap_or_else(|_| "0.0.0.0:3000".to_owned());
+    match bind.parse() {
+        Ok(addr) => addr,
+        Err(e) => startup_error(&format!("Invalid KSERVER_BIND address {bind:?}: {e}")),
     }
+}
+
+// Exact origins from KSERVER_CORS_ORIGINS; any origin is only let through
+// when it's unset, which is meant for local development
+fn cors_origins() -> AllowOrigin {
+    let Ok(origins) = std::env::var("KSERVER_CORS_ORIGINS") else {
+        event!(
+            Level::WARN,
+            "KSERVER_CORS_ORIGINS not set, allowing any origin"
+        );
+        return AllowOrigin::any();
+    };
+    let origins = origins
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| match HeaderValue::from_str(origin) {
+            Ok(value) => value,
+            Err(e) => startup_error(&format!("Invalid CORS origin {origin:?}: {e}")),
+        })
+        .collect::<Vec<_>>();
+    event!(Level::INFO, "Allowing CORS origins {:?}", origins);
+    AllowOrigin::list(origins)
+}
+
+// Peers allowed to set X-Forwarded-For, from the comma separated addresses in
+// KSERVER_TRUSTED_PROXIES. Unset means the header is never trusted.
+fn trusted_proxies() -> Vec<IpAddr> {
+    let Ok(proxies) = std::env::var("KSERVER_TRUSTED_PROXIES") else {
+        return Vec::new();
+    };
+    let proxies = proxies
+        .split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .map(|proxy| match proxy.parse() {
+            Ok(ip) => ip,
+            Err(e) => startup_error(&format!("Invalid trusted proxy {proxy:?}: {e}")),
+        })
+        .collect::<Vec<_>>();
+    event!(Level::INFO, "Trusting X-Forwarded-For from {:?}", proxies);
+    proxies
+}
 
-    let file_appender = tracing_appender::rolling::daily("/root/logs", "kserver.log");
-    let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
-    tracing_subscriber::fmt()
+/// Personal anime tracking server
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the HTTP server (default)
+    Serve(ServeArgs),
+    /// Write the TOTP enrollment QR code for `KSERVER_SECRET` to ./qr.png.
+    /// After a rotation use the QR returned by `/totp/rotate` instead
+    GenQr,
+    /// Apply pending database migrations and exit
+    Migrate,
+}
+
+#[derive(Args, Default)]
+struct ServeArgs {
+    /// Address to listen on, overrides `KSERVER_BIND`
+    #[arg(long)]
+    bind: Option<SocketAddr>,
+    /// Directory for the daily log files, overrides `KSERVER_LOG_DIR`
+    #[arg(long)]
+    log_dir: Option<String>,
+}
+
+fn gen_qr() {
+    let totp = init_totp(None);
+    std::fs::remove_file("./qr.png").unwrap_or_default();
+    let qr = totp.get_qr_png().unwrap();
+    let mut file = std::fs::File::create("./qr.png").unwrap();
+    file.write_all(&qr).unwrap();
+}
+
+fn init_logging(log_dir: Option<String>) -> WorkerGuard {
+    let log_dir = log_dir
+        .or_else(|| std::env::var("KSERVER_LOG_DIR").ok())
+        .unwrap_or_else(|| "/root/logs".to_owned());
+    // the subscriber isn't up yet, so the fallback is reported once it is
+    let log_dir_error = std::fs::create_dir_all(&log_dir).err();
+    let (non_blocking, guard) = if log_dir_error.is_none() {
+        tracing_appender::non_blocking(tracing_appender::rolling::daily(&log_dir, "kserver.log"))
+    } else {
+        tracing_appender::non_blocking(std::io::stdout())
+    };
+    let fmt = tracing_subscriber::fmt::layer()
         .with_ansi(false)
-        .with_writer(non_blocking)
-        .init();
+        .with_writer(non_blocking);
+    let fmt = if std::env::var("KSERVER_LOG_FORMAT").is_ok_and(|format| format == "json") {
+        fmt.json().boxed()
+    } else {
+        fmt.boxed()
+    };
+    let registry = tracing_subscriber::registry().with(fmt.with_filter(LevelFilter::INFO));
+    // exported spans include the debug-level ones around each database call
+    #[cfg(feature = "otel")]
+    let registry = registry.with(helper::otel::layer().with_filter(LevelFilter::DEBUG));
+    registry.init();
+    if let Some(e) = log_dir_error {
+        event!(
+            Level::WARN,
+            "Cannot create log directory {}: {}, logging to stdout instead",
+            log_dir,
+            e
+        );
+    }
+    guard
+}
+
+// Unset keeps tokio's default of one worker per core
+fn build_runtime() -> tokio::runtime::Runtime {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Ok(threads) = std::env::var("KSERVER_WORKER_THREADS") {
+        match threads.parse::<usize>() {
+            Ok(threads) if threads > 0 => {
+                builder.worker_threads(threads);
+            }
+            _ => startup_error(&format!(
+                "Invalid KSERVER_WORKER_THREADS {threads:?}: expected a positive number"
+            )),
+        }
+    }
+    builder
+        .enable_all()
+        .build()
+        .unwrap_or_else(|e| startup_error(&format!("Cannot start the async runtime: {e}")))
+}
+
+fn main() {
+    build_runtime().block_on(run());
+}
+
+async fn run() {
+    match Cli::parse()
+        .command
+        .unwrap_or(Command::Serve(ServeArgs::default()))
+    {
+        Command::Serve(args) => serve(args).await,
+        Command::GenQr => gen_qr(),
+        Command::Migrate => {
+            let _guard = init_logging(None);
+            event!(Level::INFO, "Running migrations...");
+            if let Err(e) = DbHelper::migrate().await {
+                startup_error(&format!("Migration failed: {e}"));
+            }
+            event!(Level::INFO, "Migrations applied");
+        }
+    }
+}
+
+async fn serve(args: ServeArgs) {
+    let _guard = init_logging(args.log_dir);
+
+    let addr = args.bind.unwrap_or_else(bind_addr);
+    event!(Level::INFO, "Binding to {}", addr);
 
-    let app = create_app().await;
+    let state = AppState::new().await;
+    let shutdown = state.shutdown.clone();
+    let app = create_app(state);
 
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(app.into_make_service())
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            shutdown.cancel();
+        })
         .await
         .unwrap();
+
+    event!(Level::INFO, "Shutdown complete");
+    #[cfg(feature = "otel")]
+    helper::otel::shutdown();
 }
 
-async fn create_app() -> Router {
-    let state = AppState::new().await;
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.unwrap();
+    };
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        () = ctrl_c => event!(Level::INFO, "Received SIGINT"),
+        () = terminate => event!(Level::INFO, "Received SIGTERM"),
+    }
+    event!(Level::INFO, "Shutting down, draining active connections...");
+}
+
+fn create_app(state: AppState) -> Router {
+    let request_timeout = Duration::from_secs(
+        std::env::var("KSERVER_REQUEST_TIMEOUT")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30),
+    );
 
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
         .allow_headers(Any)
-        .allow_origin(Any);
+        // browsers hide every response header outside the CORS safelist
+        // unless it is listed here
+        .expose_headers([
+            header::ETAG,
+            HeaderName::from_static("x-total-count"),
+            HeaderName::from_static("x-request-id"),
+            HeaderName::from_static("deprecation"),
+        ])
+        .allow_origin(cors_origins());
 
+    let api = Router::new()
+        .nest("/", router::create(&state))
+        .nest(router::anime::PATH, router::anime::create(&state));
+
+    // The unprefixed routes are kept as aliases of /v1 so existing clients
+    // keep working while they migrate. They answer with a Deprecation header
+    // and are to be dropped once clients have moved over; new routes are only
+    // added under /v1.
     Router::new()
+        .nest(API_PREFIX, api.clone())
+        .merge(api.route_layer(from_fn(deprecation_middleware)))
+        // long-lived streaming routes must be added after this layer
+        .route_layer(from_fn_with_state(request_timeout, timeout_middleware))
         .nest(
-            "/",
-            router::create(&state)
-        )
-        .nest(
-            router::anime::PATH,
-            router::anime::create(&state),
+            &format!("{API_PREFIX}{}", router::anime::PATH),
+            router::anime::create_streaming(),
         )
+        .nest(router::anime::PATH, router::anime::create_streaming())
+        .merge(router::docs::create())
+        .route_layer(from_fn(metrics::track_metrics))
+        .route_layer(from_fn(trace::trace_request))
         .with_state(state)
+        // the default predicate already skips bodies under 32 bytes and
+        // images; event streams are excluded so events aren't held back in the
+        // encoder. CORS stays outermost so preflights are untouched
+        .layer(CompressionLayer::new().compress_when(
+            DefaultPredicate::new().and(NotForContentType::const_new("text/event-stream")),
+        ))
         .layer(cors)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, sync::Arc, time::Duration};
+
+    use axum::{
+        body::Body,
+        http::{header, Request},
+    };
+    use tower::ServiceExt;
+
+    use crate::{create_app, helper::store::MockAnimeStore, model::TokenScope, AppState};
+
+    #[tokio::test]
+    async fn clear_token_removes_only_the_matching_token() {
+        let mut store = MockAnimeStore::new();
+        store
+            .expect_delete_token()
+            .withf(|token| token == "b")
+            .times(1)
+            .returning(|_| Ok(()));
+        let state = AppState::for_tests(Arc::new(store))
+            .with_token("a", TokenScope::ReadWrite)
+            .await
+            .with_token("b", TokenScope::ReadWrite)
+            .await
+            .with_token("c", TokenScope::Read)
+            .await;
+
+        state.clear_token("b").await.unwrap();
+
+        let token = state.token.lock().await;
+        let mut left = token.iter().map(|(t, _)| t.as_str()).collect::<Vec<_>>();
+        // swap_remove doesn't keep the order
+        left.sort_unstable();
+        assert_eq!(left, ["a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn expired_login_attempts_are_evicted() {
+        let mut state = AppState::for_tests(Arc::new(MockAnimeStore::new()));
+        state.login_window = Duration::from_millis(10);
+
+        state.reserve_login_attempt([192, 0, 2, 1].into()).await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        state.reserve_login_attempt([192, 0, 2, 2].into()).await;
+
+        let attempts = state.login_attempts.lock().await;
+        assert_eq!(attempts.len(), 1);
+        assert!(attempts.contains_key(&[192, 0, 2, 2].into()));
+    }
+
+    #[tokio::test]
+    async fn parallel_login_attempts_cannot_exceed_the_limit() {
+        let state = AppState::for_tests(Arc::new(MockAnimeStore::new()));
+        let ip: IpAddr = [192, 0, 2, 1].into();
+
+        let attempts = (0..20).map(|_| {
+            let state = state.clone();
+            tokio::spawn(async move { state.reserve_login_attempt(ip).await })
+        });
+        let mut allowed = 0;
+        for attempt in attempts.collect::<Vec<_>>() {
+            allowed += u32::from(attempt.await.unwrap());
+        }
+        assert_eq!(allowed, state.login_limit);
+    }
+
+    #[tokio::test]
+    async fn cors_exposes_the_custom_response_headers() {
+        let app = create_app(AppState::for_tests(Arc::new(MockAnimeStore::new())));
+        let request = Request::get("/v1/health")
+            .header(header::ORIGIN, "https://example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+
+        let exposed = response
+            .headers()
+            .get(header::ACCESS_CONTROL_EXPOSE_HEADERS)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        for name in ["etag", "x-total-count", "x-request-id", "deprecation"] {
+            assert!(exposed.contains(name), "{name} missing from {exposed}");
+        }
+    }
+}
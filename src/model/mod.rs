@@ -1,37 +1,58 @@
 #![allow(clippy::cast_possible_truncation)]
 #![allow(clippy::cast_precision_loss)]
 use std::{
-    collections::HashSet,
+    collections::HashMap,
     fmt::{Display, Formatter},
     hash::Hash,
+    str::FromStr,
 };
 
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tokio_postgres::Row;
+use utoipa::ToSchema;
+
+use crate::helper::db_error::DbError;
+
 pub mod request;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct WatchList {
     pub title: String,
     pub archived: bool,
     pub animes: Vec<i32>, // Corresponding to anime id
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, ToSchema)]
+pub struct WatchListSummary {
+    pub title: String,
+    pub archived: bool,
+    pub count: i32,
+}
+
+/// A watch list with its anime states inlined, in list order
+#[derive(Serialize, ToSchema)]
+pub struct WatchListExpanded {
+    pub title: String,
+    pub archived: bool,
+    pub animes: Vec<AnimeState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct Tag {
     pub name: String,
     pub count: i32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct Rating {
     pub rank: i32,
     pub total: i32,
     pub score: f32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct ImageSet {
     pub large: String,
     pub common: String,
@@ -39,7 +60,7 @@ pub struct ImageSet {
     pub small: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub struct AnimeItem {
     pub id: i32,
     pub name: String,
@@ -49,13 +70,48 @@ pub struct AnimeItem {
     pub eps: i32,
     pub total_episodes: i32,
     pub images: ImageSet,
-    #[serde(skip_serializing, default)]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
     pub tags: Option<Vec<Tag>>,
+    #[allow(dead_code)]
     #[serde(skip_serializing, default)]
     pub rating: Option<Rating>,
 }
 
+impl AnimeItem {
+    pub fn air_date(&self) -> Option<NaiveDate> {
+        let date = self.date.as_deref()?;
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+    }
+
+    pub fn season(&self) -> Option<(i32, Season)> {
+        let date = self.air_date()?;
+        Some((date.year(), Season::from_month(date.month())))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Season {
+    Winter,
+    Spring,
+    Summer,
+    Fall,
+}
+
+impl Season {
+    // Winter is January to March, and so on by quarter
+    pub fn from_month(month: u32) -> Self {
+        match month {
+            1..=3 => Self::Winter,
+            4..=6 => Self::Spring,
+            7..=9 => Self::Summer,
+            _ => Self::Fall,
+        }
+    }
+}
+
 // For use in HashSet
+#[derive(Clone, Copy, Debug)]
 pub enum Float {
     Int(i32),
     Half(i32),
@@ -64,8 +120,7 @@ pub enum Float {
 impl PartialEq for Float {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Self::Int(l0), Self::Int(r0))
-            | (Self::Half(l0), Self::Half(r0)) => l0 == r0,
+            (Self::Int(l0), Self::Int(r0)) | (Self::Half(l0), Self::Half(r0)) => l0 == r0,
             _ => false,
         }
     }
@@ -76,8 +131,9 @@ impl Eq for Float {}
 impl Hash for Float {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         match self {
-            Self::Int(i) => (i * 10).hash(state),
-            Self::Half(i) => (i * 10 + 5).hash(state),
+            // widened so the full i32 range fits without overflowing
+            Self::Int(i) => (i64::from(*i) * 10).hash(state),
+            Self::Half(i) => (i64::from(*i) * 10 + 5).hash(state),
         }
     }
 }
@@ -88,22 +144,35 @@ impl Display for Float {
             Self::Int(i) => {
                 write!(f, "{i}")
             }
-            Self::Half(i) => {
-                write!(f, "{i}.5")
+            Self::Half(_) => {
+                write!(f, "{}", self.value())
             }
         }
     }
 }
 
 impl Float {
+    pub fn value(self) -> f32 {
+        match self {
+            Self::Int(i) => i as f32,
+            Self::Half(i) => i as f32 + 0.5,
+        }
+    }
+
+    /// Rounds to the nearest half. The exact quarter points `x.25` and
+    /// `x.75` both round to `x.5`. Negative values round the same way, e.g.
+    /// `-2.6` becomes `-2.5` (`Half(-3)`).
     pub fn new(i: f32) -> Self {
-        let diff = i - i.floor();
+        // floor rather than `as`, which truncates toward zero for negatives
+        let floor = i.floor();
+        let diff = i - floor;
+        let base = floor as i32;
         if diff < 0.25 {
-            Self::Int(i as i32)
+            Self::Int(base)
         } else if diff > 0.75 {
-            Self::Int(i as i32 + 1)
+            Self::Int(base + 1)
         } else {
-            Self::Half(i as i32)
+            Self::Half(base)
         }
     }
 }
@@ -112,7 +181,7 @@ impl Serialize for Float {
     fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         match self {
             Self::Int(i) => serializer.serialize_i32(*i),
-            Self::Half(i) => serializer.serialize_f32(*i as f32 + 0.5),
+            Self::Half(_) => serializer.serialize_f32(self.value()),
         }
     }
 }
@@ -124,14 +193,374 @@ impl<'de> Deserialize<'de> for Float {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Watched episodes keyed by episode number, stored as `{ "12.5": "<RFC 3339>" }`.
+/// Rows written before timestamps were tracked hold a plain array of episode
+/// numbers; those deserialize with no watch time.
+#[derive(Default, Clone)]
+pub struct WatchedEpisodes(pub HashMap<Float, Option<DateTime<Utc>>>);
+
+impl WatchedEpisodes {
+    /// Reads the `watched_episodes` column, treating NULL as nothing watched
+    pub fn from_column(value: Option<Value>) -> Result<Self, serde_json::Error> {
+        match value {
+            None | Some(Value::Null) => Ok(Self::default()),
+            Some(value) => serde_json::from_value(value),
+        }
+    }
+
+    pub fn insert(&mut self, ep: Float) {
+        self.0.entry(ep).or_insert_with(|| Some(Utc::now()));
+    }
+
+    pub fn remove(&mut self, ep: Float) {
+        self.0.remove(&ep);
+    }
+
+    // specials such as 5.5 don't count toward total_episodes
+    pub fn whole_count(&self) -> usize {
+        self.0
+            .keys()
+            .filter(|ep| matches!(ep, Float::Int(_)))
+            .count()
+    }
+}
+
+impl Serialize for WatchedEpisodes {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().map(|(ep, at)| (ep.to_string(), at)))
+    }
+}
+
+impl<'de> Deserialize<'de> for WatchedEpisodes {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Legacy(Vec<Float>),
+            Stamped(HashMap<String, Option<DateTime<Utc>>>),
+        }
+
+        let episodes = match Repr::deserialize(deserializer)? {
+            Repr::Legacy(eps) => eps.into_iter().map(|ep| (ep, None)).collect(),
+            Repr::Stamped(eps) => eps
+                .into_iter()
+                .map(|(ep, at)| {
+                    ep.parse()
+                        .map(|ep| (Float::new(ep), at))
+                        .map_err(serde::de::Error::custom)
+                })
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(Self(episodes))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+pub enum WatchStatus {
+    #[default]
+    Watching,
+    Completed,
+    Dropped,
+    Planned,
+    OnHold,
+}
+
+impl WatchStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Watching => "Watching",
+            Self::Completed => "Completed",
+            Self::Dropped => "Dropped",
+            Self::Planned => "Planned",
+            Self::OnHold => "OnHold",
+        }
+    }
+}
+
+impl FromStr for WatchStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Watching" => Ok(Self::Watching),
+            "Completed" => Ok(Self::Completed),
+            "Dropped" => Ok(Self::Dropped),
+            "Planned" => Ok(Self::Planned),
+            "OnHold" => Ok(Self::OnHold),
+            _ => Err(format!("Unknown watch status {s}")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub enum RelationKind {
+    Sequel,
+    Prequel,
+    SideStory,
+}
+
+impl RelationKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Sequel => "Sequel",
+            Self::Prequel => "Prequel",
+            Self::SideStory => "SideStory",
+        }
+    }
+
+    /// How the other anime sees the relation: B is A's sequel when A is B's prequel
+    pub fn inverse(self) -> Self {
+        match self {
+            Self::Sequel => Self::Prequel,
+            Self::Prequel => Self::Sequel,
+            Self::SideStory => Self::SideStory,
+        }
+    }
+}
+
+impl FromStr for RelationKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Sequel" => Ok(Self::Sequel),
+            "Prequel" => Ok(Self::Prequel),
+            "SideStory" => Ok(Self::SideStory),
+            _ => Err(format!("Unknown relation kind {s}")),
+        }
+    }
+}
+
+/// One stored link: `related_id` is the `kind` of `anime_id`, e.g. its sequel
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+pub struct Relation {
+    pub anime_id: i32,
+    pub related_id: i32,
+    pub kind: RelationKind,
+}
+
+/// Animes related to one entry, grouped by what they are to it
+#[derive(Serialize, Default, ToSchema)]
+pub struct AnimeRelations {
+    pub sequels: Vec<AnimeState>,
+    pub prequels: Vec<AnimeState>,
+    pub side_stories: Vec<AnimeState>,
+}
+
+/// What a session token may do; `read` tokens are limited to GET requests
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    Read,
+    #[default]
+    ReadWrite,
+}
+
+impl TokenScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::ReadWrite => "readwrite",
+        }
+    }
+}
+
+impl FromStr for TokenScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Self::Read),
+            "readwrite" => Ok(Self::ReadWrite),
+            _ => Err(format!("Unknown token scope {s}")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct AnimeState {
     pub anime_id: i32,
     pub anime_item: AnimeItem,
     pub favorite: bool,
-    pub watched_episodes: HashSet<Float>,
+    #[schema(value_type = HashMap<String, Option<DateTime<Utc>>>)]
+    pub watched_episodes: WatchedEpisodes,
     pub visibility: bool,
-    pub rating: Option<i32>,
+    #[schema(value_type = Option<f32>)]
+    pub rating: Option<Float>,
+    pub status: WatchStatus,
+    // missing from exports made before ranking existed
+    #[serde(default)]
+    pub personal_rank: Option<i32>,
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+/// Pushed to `/anime/events` subscribers after a write
+#[derive(Serialize, Clone, Debug, ToSchema)]
+pub struct LibraryEvent {
+    #[schema(value_type = String)]
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anime_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub watch_list_name: Option<String>,
+}
+
+impl LibraryEvent {
+    pub fn anime(kind: &'static str, anime_id: i32) -> Self {
+        Self {
+            kind,
+            anime_id: Some(anime_id),
+            watch_list_name: None,
+        }
+    }
+
+    pub fn watch_list(watch_list_name: &str) -> Self {
+        Self {
+            kind: "watch_list_update",
+            anime_id: None,
+            watch_list_name: Some(watch_list_name.to_owned()),
+        }
+    }
+
+    pub fn ranking() -> Self {
+        Self {
+            kind: "ranking_update",
+            anime_id: None,
+            watch_list_name: None,
+        }
+    }
+
+    pub fn import() -> Self {
+        Self {
+            kind: "import",
+            anime_id: None,
+            watch_list_name: None,
+        }
+    }
+}
+
+// 2 added relations
+pub const EXPORT_VERSION: u32 = 2;
+pub const MIN_EXPORT_VERSION: u32 = 1;
+
+// far beyond any real series, but keeps watched maps bounded when episode
+// numbers or counts come from clients or Bangumi
+pub const MAX_EPISODE: i32 = 10_000;
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct LibraryExport {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub watch_lists: Vec<WatchList>,
+    pub anime_states: Vec<AnimeState>,
+    // absent from version 1 exports
+    #[serde(default)]
+    pub relations: Vec<Relation>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct Stats {
+    pub total_animes: i64,
+    pub total_watch_lists: i64,
+    pub favorites: i64,
+    pub by_status: HashMap<String, i64>,
+    pub episodes_watched: i64,
+    pub average_rating: Option<f64>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BacklogItem {
+    #[serde(flatten)]
+    pub anime_state: AnimeState,
+    pub remaining: i32,
+}
+
+/// Annual summary built from per-episode watch times. Episodes watched before
+/// timestamps were tracked aren't counted in any year.
+#[derive(Serialize, ToSchema)]
+pub struct YearInReview {
+    pub year: i32,
+    pub episodes_watched: i64,
+    /// Twelve entries, January first
+    pub episodes_per_month: Vec<i64>,
+    pub series_completed: i64,
+    pub hours_watched: f64,
+    pub top_rated: Vec<AnimeState>,
+}
+
+/// Snapshot of the database connection pool
+#[derive(Serialize, Debug, Clone, Copy, ToSchema)]
+pub struct PoolStatus {
+    pub max_size: usize,
+    /// Connections currently open, idle or in use
+    pub size: usize,
+    /// Idle connections ready to be checked out
+    pub available: usize,
+    /// Requests queued for a connection
+    pub waiting: usize,
+}
+
+impl PoolStatus {
+    /// Every connection is open and busy, so new requests have to queue
+    pub fn exhausted(&self) -> bool {
+        self.available == 0 && self.size >= self.max_size
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct Readiness {
+    #[schema(value_type = String)]
+    pub status: &'static str,
+    pub pool: PoolStatus,
+    /// Outcome of a `SELECT 1`, absent when skipped because the pool is exhausted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_ok: Option<bool>,
+}
+
+/// Outcome of one id in a bulk Bangumi import
+#[derive(Serialize, Debug, ToSchema)]
+pub struct BulkInsertResult {
+    pub bgm_id: i32,
+    pub inserted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct Progress {
+    pub watched: i32,
+    pub total: i32,
+    pub remaining: i32,
+    pub percent: f32,
+}
+
+impl From<&AnimeState> for Progress {
+    fn from(value: &AnimeState) -> Self {
+        let watched = i32::try_from(value.watched_episodes.whole_count()).unwrap_or(i32::MAX);
+        let total = value.anime_item.total_episodes;
+        // an unknown episode count is reported as no progress
+        let percent = if total > 0 {
+            (watched as f32 / total as f32 * 100.0).min(100.0)
+        } else {
+            0.0
+        };
+        Self {
+            watched,
+            total,
+            remaining: (total - watched).max(0),
+            percent,
+        }
+    }
+}
+
+impl From<&Row> for Tag {
+    fn from(value: &Row) -> Self {
+        Self {
+            name: value.get(0),
+            count: value.get(1),
+        }
+    }
 }
 
 impl From<&Row> for WatchList {
@@ -144,18 +573,45 @@ impl From<&Row> for WatchList {
     }
 }
 
-impl From<&Row> for AnimeState {
+impl From<&Row> for WatchListSummary {
     fn from(value: &Row) -> Self {
-        let watched_episodes: Value = value.get(3);
-        let watched_episodes: HashSet<Float> = serde_json::from_value(watched_episodes).unwrap();
-
         Self {
-            anime_id: value.get(0),
-            anime_item: serde_json::from_value(value.get(1)).unwrap(),
+            title: value.get(0),
+            archived: value.get(1),
+            count: value.get(2),
+        }
+    }
+}
+
+impl TryFrom<&Row> for AnimeState {
+    type Error = DbError;
+
+    fn try_from(value: &Row) -> Result<Self, Self::Error> {
+        let anime_id = value.get(0);
+        // without its item the anime can't be shown at all, unlike episodes
+        let anime_item = serde_json::from_value(value.get(1)).map_err(|e| {
+            tracing::warn!("Malformed anime_item for anime {}: {}", anime_id, e);
+            DbError::MalformedAnimeItem(anime_id)
+        })?;
+        // a bad column shouldn't take down every listing the row appears in
+        let watched_episodes = WatchedEpisodes::from_column(value.get(3)).unwrap_or_else(|e| {
+            tracing::warn!("Malformed watched_episodes for anime {}: {}", anime_id, e);
+            WatchedEpisodes::default()
+        });
+
+        Ok(Self {
+            anime_id,
+            anime_item,
             favorite: value.get(2),
             watched_episodes,
             visibility: value.get(4),
-            rating: value.get(5),
-        }
+            rating: value.get::<_, Option<f32>>(5).map(Float::new),
+            status: value.get::<_, &str>(6).parse().unwrap_or_default(),
+            personal_rank: value.get("personal_rank"),
+            note: value.get("note"),
+        })
     }
 }
+
+#[cfg(test)]
+mod tests;
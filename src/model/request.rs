@@ -1,58 +1,311 @@
 #![allow(clippy::module_name_repetitions)]
 use serde::Deserialize;
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Deserialize, Debug)]
+use super::{Float, RelationKind, Season, TokenScope, WatchStatus};
+
+#[derive(Deserialize, Debug, ToSchema)]
 pub struct AnimeWatchListRequest {
     pub anime_id: i32,
     pub watch_list_name: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct WatchListRequest {
     pub watch_list_name: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct AddRelationRequest {
+    pub anime_id: i32,
+    pub related_id: i32,
+    /// What `related_id` is to `anime_id`
+    pub kind: RelationKind,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RelationRequest {
+    pub anime_id: i32,
+    pub related_id: i32,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct AddWatchListsRequest {
+    pub names: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    #[default]
+    Error,
+    Skip,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OnConflictRequest {
+    #[serde(default)]
+    pub on_conflict: OnConflict,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct RenameWatchListRequest {
+    pub old_name: String,
+    pub new_name: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct MoveAnimeRequest {
+    pub anime_id: i32,
+    pub from_watch_list_name: String,
+    pub to_watch_list_name: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
 pub struct LogInRequest {
     pub otp: String,
+    #[serde(default)]
+    pub scope: TokenScope,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
 pub struct LogOutRequest {
     pub token: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
 pub struct UpdateEpisodeWatchedStateRequest {
     pub anime_id: i32,
-    pub ep: i32,
+    #[schema(value_type = f32)]
+    pub ep: Float,
     pub watched: bool,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct UpdateEpisodesWatchedStateRequest {
+    pub anime_id: i32,
+    #[schema(value_type = Vec<f32>)]
+    pub eps: Vec<Float>,
+    pub watched: bool,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct MarkAllWatchedRequest {
+    pub anime_id: i32,
+    #[serde(default = "default_true")]
+    pub watched: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
 pub struct UpdateWatchListArchivedRequest {
     pub watch_list_name: String,
     pub archived: bool,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct UpdateWatchListVisibilityRequest {
+    pub watch_list_name: String,
+    pub visible: bool,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
 pub struct UpdateAnimeVisibilityRequest {
     pub anime_id: i32,
     pub visible: bool,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct PostUpdateAnimeFavoriteRequest {
+    pub anime_id: i32,
+    pub favorite: bool,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct PostUpdateAnimeNoteRequest {
+    pub anime_id: i32,
+    pub note: Option<String>,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct PostUpdateAnimeStatusRequest {
+    pub anime_id: i32,
+    pub status: WatchStatus,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WatchStatusRequest {
+    pub status: WatchStatus,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RandomAnimeRequest {
+    pub status: Option<WatchStatus>,
+    #[serde(default)]
+    pub unfinished: bool,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FeedTokenRequest {
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct AnimeIdRequest {
     pub anime_id: i32,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
 pub struct GetAnimeStatesRequest {
     pub anime_ids: Vec<i32>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct ReorderRankingRequest {
+    pub anime_ids: Vec<i32>,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
 pub struct PostUpdateAnimeRatingRequest {
     pub anime_id: i32,
-    pub rating: i32,
+    // kept raw so the range is checked before rounding to a half
+    pub rating: f32,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageRequest {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LimitRequest {
+    pub limit: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchRequest {
+    pub q: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    Rating,
+    Date,
+    Name,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SortRequest {
+    pub sort: Option<SortKey>,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct BgmIdRequest {
+    pub bgm_id: i32,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct BgmIdsRequest {
+    pub ids: Vec<i32>,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MinimalRequest {
+    #[serde(default)]
+    pub minimal: bool,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct InsertAnimeItemRequest {
+    #[serde(default)]
+    pub insert_only: bool,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TagRequest {
+    pub tag: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct AnimeTagRequest {
+    pub anime_id: i32,
+    pub name: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    #[default]
+    Merge,
+    Replace,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImportRequest {
+    #[serde(default)]
+    pub mode: ImportMode,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListRequest {
+    #[serde(default)]
+    pub summary: bool,
+    pub archived: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SeasonRequest {
+    pub year: i32,
+    pub season: Season,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AiringRequest {
+    pub weeks: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct YearRequest {
+    pub year: i32,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TopRatedRequest {
+    pub limit: Option<i64>,
+    pub order: Option<SortOrder>,
 }
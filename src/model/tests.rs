@@ -0,0 +1,82 @@
+use serde_json::json;
+
+use super::{AnimeState, Float, WatchStatus, WatchedEpisodes};
+use crate::helper::db::tests::anime_item;
+
+#[test]
+fn float_new_rounds_to_the_nearest_half() {
+    let cases = [
+        (0.0, Float::Int(0)),
+        (0.2, Float::Int(0)),
+        (0.25, Float::Half(0)),
+        (0.5, Float::Half(0)),
+        (0.75, Float::Half(0)),
+        (0.8, Float::Int(1)),
+        (5.5, Float::Half(5)),
+        (12.0, Float::Int(12)),
+        (-0.1, Float::Int(0)),
+        (-0.5, Float::Half(-1)),
+        (-2.2, Float::Int(-2)),
+        (-2.25, Float::Half(-3)),
+        (-2.6, Float::Half(-3)),
+        (-2.75, Float::Half(-3)),
+        (-2.8, Float::Int(-3)),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(Float::new(input), expected, "Float::new({input})");
+    }
+}
+
+#[test]
+fn float_survives_a_json_round_trip() {
+    let values = [
+        Float::Int(0),
+        Float::Int(12),
+        Float::Int(-3),
+        Float::Half(0),
+        Float::Half(5),
+        Float::Half(-1),
+    ];
+    for value in values {
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Float>(&json).unwrap(),
+            value,
+            "{json}"
+        );
+    }
+}
+
+#[test]
+fn half_episodes_serialize_as_decimals() {
+    assert_eq!(serde_json::to_string(&Float::Half(5)).unwrap(), "5.5");
+
+    let mut watched_episodes = WatchedEpisodes::default();
+    watched_episodes.0.insert(Float::Half(5), None);
+    let state = AnimeState {
+        anime_id: 1,
+        anime_item: anime_item(1),
+        favorite: false,
+        watched_episodes,
+        visibility: true,
+        rating: Some(Float::Half(5)),
+        status: WatchStatus::Watching,
+        personal_rank: None,
+        note: None,
+    };
+    let json = serde_json::to_value(&state).unwrap();
+    assert_eq!(json["rating"], json!(5.5));
+    assert_eq!(json["watched_episodes"], json!({ "5.5": null }));
+}
+
+#[test]
+fn hashing_extreme_episodes_does_not_overflow() {
+    let episodes = [
+        Float::Int(i32::MAX),
+        Float::Half(i32::MAX),
+        Float::Int(i32::MIN),
+    ]
+    .into_iter()
+    .collect::<std::collections::HashSet<_>>();
+    assert_eq!(episodes.len(), 3);
+}
@@ -1,88 +1,432 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    convert::Infallible,
+    hash::{Hash, Hasher},
+};
+
 use axum::{
-    extract::{State, Query},
-    http::StatusCode,
+    extract::{DefaultBodyLimit, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     middleware::from_fn_with_state,
-    routing::{get, post},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{delete, get, post},
     Json, Router,
 };
+use chrono::Utc;
+use futures_util::{stream, Stream, StreamExt};
+use subtle::ConstantTimeEq;
+use tokio::sync::broadcast::error::RecvError;
+use tower_http::limit::RequestBodyLimitLayer;
 use tracing::event;
 
 use crate::{
-    auth_middleware,
+    auth_middleware, gen_token,
+    helper::{bangumi, ical},
     model::{
         request::{
-            AnimeWatchListRequest, GetAnimeStatesRequest, PostUpdateAnimeRatingRequest,
+            AddRelationRequest, AddWatchListsRequest, AiringRequest, AnimeIdRequest,
+            AnimeTagRequest, AnimeWatchListRequest, BgmIdRequest, BgmIdsRequest, FeedTokenRequest,
+            GetAnimeStatesRequest, ImportRequest, InsertAnimeItemRequest, LimitRequest,
+            ListRequest, MarkAllWatchedRequest, MinimalRequest, MoveAnimeRequest,
+            OnConflictRequest, PageRequest, PostUpdateAnimeFavoriteRequest,
+            PostUpdateAnimeNoteRequest, PostUpdateAnimeRatingRequest, PostUpdateAnimeStatusRequest,
+            RandomAnimeRequest, RelationRequest, RenameWatchListRequest, ReorderRankingRequest,
+            SearchRequest, SeasonRequest, SortOrder, SortRequest, TagRequest, TopRatedRequest,
             UpdateAnimeVisibilityRequest, UpdateEpisodeWatchedStateRequest,
-            UpdateWatchListArchivedRequest, WatchListRequest, AnimeIdRequest,
+            UpdateEpisodesWatchedStateRequest, UpdateWatchListArchivedRequest,
+            UpdateWatchListVisibilityRequest, WatchListRequest, WatchStatusRequest, YearRequest,
         },
-        AnimeItem, AnimeState, WatchList,
+        AnimeItem, AnimeRelations, AnimeState, BacklogItem, BulkInsertResult, Float, LibraryExport,
+        Progress, Stats, Tag, WatchList, WatchListExpanded, YearInReview, EXPORT_VERSION,
+        MAX_EPISODE, MIN_EXPORT_VERSION,
     },
-    AppState,
+    status, AppState, AuthStatus,
 };
 
 use super::Result;
 
 pub const PATH: &str = "/anime";
 
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MIN_RATING: f32 = 1.0;
+const MAX_RATING: f32 = 10.0;
+const MAX_NOTE_CHARS: usize = 4000;
+const MAX_WATCH_LIST_NAME_CHARS: usize = 100;
+const DEFAULT_TOP_LIMIT: i64 = 10;
+const MAX_TOP_LIMIT: i64 = 100;
+// one season's worth of weekly episodes
+const DEFAULT_AIRING_WEEKS: i64 = 13;
+
+// one route per line, so the length grows with the API rather than with logic
+#[allow(clippy::too_many_lines)]
 pub fn create(state: &AppState) -> Router<AppState> {
     Router::new()
         .route("/insert_anime_item", post(post_insert_item))
+        .route("/insert_by_bgm_id", post(post_insert_by_bgm_id))
+        .route("/bulk_insert_by_bgm_ids", post(post_bulk_insert_by_bgm_ids))
+        .route(
+            "/update_anime_item",
+            post(post_update_anime_item).put(post_update_anime_item),
+        )
         .route("/add_item_to_watch_list", post(post_add_item_to_watch_list))
+        .route("/move_anime", post(post_move_anime))
         .route("/add_new_watch_list", post(post_add_new_watch_list))
+        .route("/add_watch_lists", post(post_add_watch_lists))
         .route(
             "/update_episode_watched_state",
-            post(post_update_episode_watched_state),
+            post(post_update_episode_watched_state).put(post_update_episode_watched_state),
         )
+        .route(
+            "/update_episodes_watched_state",
+            post(post_update_episodes_watched_state).put(post_update_episodes_watched_state),
+        )
+        .route("/mark_all_watched", post(post_mark_all_watched))
         .route(
             "/update_anime_visibility",
-            post(post_update_anime_visibility),
+            post(post_update_anime_visibility).put(post_update_anime_visibility),
+        )
+        .route(
+            "/update_watch_list_visibility",
+            post(post_update_watch_list_visibility).put(post_update_watch_list_visibility),
+        )
+        .route(
+            "/update_anime_favorite",
+            post(post_update_anime_favorite).put(post_update_anime_favorite),
         )
+        .route(
+            "/update_anime_note",
+            post(post_update_anime_note).put(post_update_anime_note),
+        )
+        .route(
+            "/update_anime_status",
+            post(post_update_anime_status).put(post_update_anime_status),
+        )
+        .route("/archive_watch_list", post(post_archive_watch_list))
         .route(
             "/update_watch_list_archived",
-            post(post_update_watch_list_archived),
+            post(post_update_watch_list_archived).put(post_update_watch_list_archived),
         )
         .route("/delete_watch_list", post(post_delete_watch_list))
+        .route(
+            "/rename_watch_list",
+            post(post_rename_watch_list).put(post_rename_watch_list),
+        )
         .route(
             "/delete_anime_state_from_watch_list",
             post(post_delete_anime_state_from_watch_list),
         )
-        .route("/update_anime_rating", post(post_update_anime_rating))
+        .route(
+            "/update_anime_rating",
+            post(post_update_anime_rating).put(post_update_anime_rating),
+        )
+        .route("/reorder_ranking", post(post_reorder_ranking))
+        .route("/search", get(get_search_anime_by_name))
+        .route("/tags", get(get_all_tags))
+        .route("/by_tag", get(get_query_animes_by_tag))
+        .route("/add_tag", post(post_add_tag))
+        .route("/add_relation", post(post_add_relation))
+        .route("/relation", delete(delete_relation))
+        .route("/remove_tag", post(post_remove_tag))
+        .route("/export", get(get_export))
+        .route("/watch_list/:name", delete(delete_watch_list))
+        .route("/anime_state/:id", delete(delete_anime_state))
+        .route("/share_watch_list", post(post_share_watch_list))
+        .route("/share/:token", delete(delete_share_token))
         .layer(from_fn_with_state(state.clone(), auth_middleware))
         .route("/list", get(get_all_list))
         .route("/get", get(get_query_anime_by_id))
+        .route("/progress", get(get_query_anime_progress))
         .route("/get_anime_states", post(post_query_anime_states))
         .route("/all", get(get_query_all_anime_states))
+        .route("/favorites", get(get_query_favorite_animes))
+        .route("/by_status", get(get_query_animes_by_status))
+        .route("/completed", get(get_query_completed))
+        .route("/continue_watching", get(get_query_continue_watching))
+        .route("/stats", get(get_stats))
+        .route("/random", get(get_random_anime))
+        .route("/by_season", get(get_query_animes_by_season))
+        .route("/airing", get(get_query_airing))
+        .route("/top", get(get_query_top_rated))
+        .route("/ranked", get(get_query_ranked))
+        .route("/:id/relations", get(get_query_relations))
+        .route("/backlog", get(get_query_backlog))
+        .route("/year_in_review", get(get_year_in_review))
+        .route("/calendar.ics", get(get_calendar))
+        .route("/get_watch_list", get(get_query_watch_list_by_name))
+        .route("/get_watch_list_full", get(get_query_watch_list_expanded))
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(state.max_body_bytes))
+        // added after the general limit since a whole library won't fit in it
         .route(
-            "/get_watch_list",
-            get(get_query_watch_list_by_name),
+            "/import",
+            post(post_import)
+                .route_layer(from_fn_with_state(state.clone(), auth_middleware))
+                .route_layer(DefaultBodyLimit::disable())
+                .layer(RequestBodyLimitLayer::new(state.max_import_body_bytes)),
         )
 }
 
-async fn get_all_list(State(app_state): State<AppState>) -> Result<Json<Vec<WatchList>>> {
+// Routes that hold the connection open, mounted outside the request timeout
+pub fn create_streaming() -> Router<AppState> {
+    Router::new().route("/events", get(get_events))
+}
+
+// Updates answer with the new state so clients can skip a refetch, unless
+// they asked for `?minimal=true`
+fn updated(state: AnimeState, minimal: bool) -> Response {
+    if minimal {
+        StatusCode::OK.into_response()
+    } else {
+        Json(state).into_response()
+    }
+}
+
+// Ids come from Bangumi and start at 1, anything else can't match a row
+fn validate_anime_id(anime_id: i32) -> Result<()> {
+    if anime_id <= 0 {
+        return Err(status!(
+            BAD_REQUEST,
+            "InvalidAnimeId",
+            "Anime id must be positive, got {}",
+            anime_id
+        ));
+    }
+    Ok(())
+}
+
+fn validate_episode(ep: Float) -> Result<()> {
+    let value = f64::from(ep.value());
+    if value <= 0.0 || value > f64::from(MAX_EPISODE) {
+        return Err(status!(
+            BAD_REQUEST,
+            "InvalidEpisode",
+            "Episode must be between 0 and {}, got {}",
+            MAX_EPISODE,
+            ep
+        ));
+    }
+    Ok(())
+}
+
+// Names end up in paths and query strings, so they're trimmed and kept short
+fn validate_watch_list_name(name: &str) -> Result<&str> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(status!(
+            BAD_REQUEST,
+            "InvalidWatchListName",
+            "Watch list name must not be empty"
+        ));
+    }
+    let len = name.chars().count();
+    if len > MAX_WATCH_LIST_NAME_CHARS {
+        return Err(status!(
+            BAD_REQUEST,
+            "InvalidWatchListName",
+            "Watch list name is {} characters, at most {} are allowed",
+            len,
+            MAX_WATCH_LIST_NAME_CHARS
+        ));
+    }
+    Ok(name)
+}
+
+// Tags are stored trimmed, so removal has to match the same way
+fn validate_tag_name(name: &str) -> Result<&str> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(status!(
+            BAD_REQUEST,
+            "EmptyTag",
+            "Tag name must not be empty"
+        ));
+    }
+    Ok(name)
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/list",
+    tag = "anime",
+    params(ListRequest),
+    responses(
+        (status = 200, description = "All watch lists, or their summaries with `summary=true`", body = [WatchList]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_all_list(
+    State(app_state): State<AppState>,
+    Query(ListRequest { summary, archived }): Query<ListRequest>,
+) -> Result<Response> {
     let db = app_state.db_helper.clone();
 
-    let result = db.get_all_list().await?;
+    if summary {
+        let result = db.get_all_list_summaries(archived).await?;
+        return Ok(Json(result).into_response());
+    }
 
-    Ok(Json(result))
+    let result = db.get_all_list(archived).await?;
+
+    Ok(Json(result).into_response())
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/anime/insert_anime_item",
+    tag = "anime",
+    params(InsertAnimeItemRequest),
+    request_body = AnimeItem,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 201, description = "Anime inserted or updated"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn post_insert_item(
     State(app_state): State<AppState>,
+    Query(InsertAnimeItemRequest { insert_only }): Query<InsertAnimeItemRequest>,
     Json(req): Json<AnimeItem>,
 ) -> Result<StatusCode> {
     let db = app_state.db_helper.clone();
+    validate_anime_id(req.id)?;
     event!(tracing::Level::INFO, "Inserting anime item: {:?}", req);
 
-    db.insert_anime_item(req).await?;
+    if insert_only {
+        db.insert_new_anime_item(req).await?;
+    } else {
+        db.insert_anime_item(req).await?;
+    }
+
+    Ok(StatusCode::CREATED)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/insert_by_bgm_id",
+    tag = "anime",
+    request_body = BgmIdRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 201, description = "Anime fetched from Bangumi and inserted"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_insert_by_bgm_id(
+    State(app_state): State<AppState>,
+    Json(BgmIdRequest { bgm_id }): Json<BgmIdRequest>,
+) -> Result<StatusCode> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(bgm_id)?;
+    event!(
+        tracing::Level::INFO,
+        "Inserting anime by bangumi id: {}",
+        bgm_id
+    );
+
+    let item = bangumi::fetch_subject(bgm_id).await?;
+    db.insert_anime_item(item).await?;
 
     Ok(StatusCode::CREATED)
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/anime/bulk_insert_by_bgm_ids",
+    tag = "anime",
+    request_body = BgmIdsRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Outcome of each id; failed fetches don't stop the rest", body = [BulkInsertResult]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_bulk_insert_by_bgm_ids(
+    State(app_state): State<AppState>,
+    Json(BgmIdsRequest { ids }): Json<BgmIdsRequest>,
+) -> Result<Json<Vec<BulkInsertResult>>> {
+    let db = app_state.db_helper.clone();
+    event!(
+        tracing::Level::INFO,
+        "Bulk inserting {} animes by bangumi id",
+        ids.len()
+    );
+
+    let mut items = Vec::with_capacity(ids.len());
+    let mut results = Vec::with_capacity(ids.len());
+    for (bgm_id, fetched) in bangumi::fetch_subjects(&ids).await {
+        match fetched {
+            Ok(item) => {
+                items.push(item);
+                results.push(BulkInsertResult {
+                    bgm_id,
+                    inserted: true,
+                    error: None,
+                });
+            }
+            Err(e) => {
+                event!(
+                    tracing::Level::WARN,
+                    "Failed to fetch bangumi subject {}: {}",
+                    bgm_id,
+                    e
+                );
+                results.push(BulkInsertResult {
+                    bgm_id,
+                    inserted: false,
+                    error: Some(e.to_string()),
+                });
+            }
+        }
+    }
+    db.insert_anime_items(&items).await?;
+
+    Ok(Json(results))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/update_anime_item",
+    tag = "anime",
+    request_body = AnimeItem,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Anime item updated"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_update_anime_item(
+    State(app_state): State<AppState>,
+    Json(req): Json<AnimeItem>,
+) -> Result<StatusCode> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(req.id)?;
+    event!(tracing::Level::INFO, "Updating anime item: {:?}", req);
+
+    db.update_anime_item(req.id, req).await?;
+
+    Ok(StatusCode::OK)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/add_item_to_watch_list",
+    tag = "anime",
+    request_body = AnimeWatchListRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 201, description = "Anime added to the watch list"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn post_add_item_to_watch_list(
     State(app_state): State<AppState>,
     Json(req): Json<AnimeWatchListRequest>,
 ) -> Result<StatusCode> {
     let db = app_state.db_helper.clone();
+    validate_anime_id(req.anime_id)?;
     event!(
         tracing::Level::INFO,
         "Adding anime item to watch list: {:?}",
@@ -95,29 +439,189 @@ async fn post_add_item_to_watch_list(
     Ok(StatusCode::CREATED)
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/anime/move_anime",
+    tag = "anime",
+    request_body = MoveAnimeRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Anime moved between watch lists"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_move_anime(
+    State(app_state): State<AppState>,
+    Json(req): Json<MoveAnimeRequest>,
+) -> Result<StatusCode> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(req.anime_id)?;
+    event!(
+        tracing::Level::INFO,
+        "Moving anime between watch lists: {:?}",
+        req
+    );
+
+    db.move_anime_between_lists(
+        req.anime_id,
+        &req.from_watch_list_name,
+        &req.to_watch_list_name,
+    )
+    .await?;
+
+    Ok(StatusCode::OK)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/add_new_watch_list",
+    tag = "anime",
+    request_body = WatchListRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 201, description = "Watch list created"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn post_add_new_watch_list(
     State(app_state): State<AppState>,
     Json(req): Json<WatchListRequest>,
 ) -> Result<StatusCode> {
     let db = app_state.db_helper.clone();
+    let watch_list_name = validate_watch_list_name(&req.watch_list_name)?;
 
-    db.add_new_watch_list(&req.watch_list_name).await?;
+    db.add_new_watch_list(watch_list_name).await?;
 
     Ok(StatusCode::CREATED)
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/anime/add_watch_lists",
+    tag = "anime",
+    request_body = AddWatchListsRequest,
+    params(OnConflictRequest),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 201, description = "Names of the watch lists actually created", body = [String]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_add_watch_lists(
+    State(app_state): State<AppState>,
+    Query(OnConflictRequest { on_conflict }): Query<OnConflictRequest>,
+    Json(req): Json<AddWatchListsRequest>,
+) -> Result<(StatusCode, Json<Vec<String>>)> {
+    let db = app_state.db_helper.clone();
+    let names = req
+        .names
+        .iter()
+        .map(|name| validate_watch_list_name(name).map(str::to_owned))
+        .collect::<Result<Vec<_>>>()?;
+
+    let created = db.add_watch_lists(&names, on_conflict).await?;
+
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/update_episode_watched_state",
+    tag = "anime",
+    request_body = UpdateEpisodeWatchedStateRequest,
+    params(MinimalRequest),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Episode state updated", body = AnimeState),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn post_update_episode_watched_state(
     State(app_state): State<AppState>,
+    Query(MinimalRequest { minimal }): Query<MinimalRequest>,
     Json(req): Json<UpdateEpisodeWatchedStateRequest>,
-) -> Result<StatusCode> {
+) -> Result<Response> {
     let db = app_state.db_helper.clone();
+    validate_anime_id(req.anime_id)?;
+    validate_episode(req.ep)?;
 
-    db.update_episode_watched_state(req.anime_id, req.ep, req.watched)
+    let state = db
+        .update_episode_watched_state(req.anime_id, req.ep, req.watched)
         .await?;
 
-    Ok(StatusCode::OK)
+    Ok(updated(state, minimal))
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/anime/update_episodes_watched_state",
+    tag = "anime",
+    request_body = UpdateEpisodesWatchedStateRequest,
+    params(MinimalRequest),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Episode states updated", body = AnimeState),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_update_episodes_watched_state(
+    State(app_state): State<AppState>,
+    Query(MinimalRequest { minimal }): Query<MinimalRequest>,
+    Json(req): Json<UpdateEpisodesWatchedStateRequest>,
+) -> Result<Response> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(req.anime_id)?;
+    for &ep in &req.eps {
+        validate_episode(ep)?;
+    }
+
+    let state = db
+        .update_episodes_watched_state(req.anime_id, &req.eps, req.watched)
+        .await?;
+
+    Ok(updated(state, minimal))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/mark_all_watched",
+    tag = "anime",
+    request_body = MarkAllWatchedRequest,
+    params(MinimalRequest),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "All episodes updated", body = AnimeState),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_mark_all_watched(
+    State(app_state): State<AppState>,
+    Query(MinimalRequest { minimal }): Query<MinimalRequest>,
+    Json(req): Json<MarkAllWatchedRequest>,
+) -> Result<Response> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(req.anime_id)?;
+
+    let state = if req.watched {
+        db.mark_all_watched(req.anime_id).await?
+    } else {
+        db.unmark_all(req.anime_id).await?
+    };
+
+    Ok(updated(state, minimal))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/update_watch_list_archived",
+    tag = "anime",
+    request_body = UpdateWatchListArchivedRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Watch list archive flag updated"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn post_update_watch_list_archived(
     State(app_state): State<AppState>,
     Json(req): Json<UpdateWatchListArchivedRequest>,
@@ -130,29 +634,274 @@ async fn post_update_watch_list_archived(
     Ok(StatusCode::OK)
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/anime/update_anime_visibility",
+    tag = "anime",
+    request_body = UpdateAnimeVisibilityRequest,
+    params(MinimalRequest),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Visibility updated", body = AnimeState),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn post_update_anime_visibility(
     State(app_state): State<AppState>,
+    Query(MinimalRequest { minimal }): Query<MinimalRequest>,
     Json(req): Json<UpdateAnimeVisibilityRequest>,
+) -> Result<Response> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(req.anime_id)?;
+
+    let state = db
+        .update_anime_visibility(req.anime_id, req.visible)
+        .await?;
+
+    Ok(updated(state, minimal))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/update_watch_list_visibility",
+    tag = "anime",
+    request_body = UpdateWatchListVisibilityRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Visibility updated for every anime in the watch list"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_update_watch_list_visibility(
+    State(app_state): State<AppState>,
+    Json(req): Json<UpdateWatchListVisibilityRequest>,
 ) -> Result<StatusCode> {
     let db = app_state.db_helper.clone();
 
-    db.update_anime_visibility(req.anime_id, req.visible)
+    db.update_watch_list_visibility(&req.watch_list_name, req.visible)
         .await?;
 
     Ok(StatusCode::OK)
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/anime/archive_watch_list",
+    tag = "anime",
+    request_body = WatchListRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 204, description = "Watch list archived and all its animes hidden"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_archive_watch_list(
+    State(app_state): State<AppState>,
+    Json(req): Json<WatchListRequest>,
+) -> Result<StatusCode> {
+    let db = app_state.db_helper.clone();
+
+    db.archive_and_hide(&req.watch_list_name).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/update_anime_favorite",
+    tag = "anime",
+    request_body = PostUpdateAnimeFavoriteRequest,
+    params(MinimalRequest),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Favorite flag updated", body = AnimeState),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_update_anime_favorite(
+    State(app_state): State<AppState>,
+    Query(MinimalRequest { minimal }): Query<MinimalRequest>,
+    Json(req): Json<PostUpdateAnimeFavoriteRequest>,
+) -> Result<Response> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(req.anime_id)?;
+
+    let state = db.update_anime_favorite(req.anime_id, req.favorite).await?;
+
+    Ok(updated(state, minimal))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/update_anime_note",
+    tag = "anime",
+    request_body = PostUpdateAnimeNoteRequest,
+    params(MinimalRequest),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Note updated, a null note clears it", body = AnimeState),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_update_anime_note(
+    State(app_state): State<AppState>,
+    Query(MinimalRequest { minimal }): Query<MinimalRequest>,
+    Json(PostUpdateAnimeNoteRequest { anime_id, note }): Json<PostUpdateAnimeNoteRequest>,
+) -> Result<Response> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(anime_id)?;
+    if let Some(len) = note.as_ref().map(|note| note.chars().count()) {
+        if len > MAX_NOTE_CHARS {
+            return Err(status!(
+                BAD_REQUEST,
+                "NoteTooLong",
+                "Note is {} characters, at most {} are allowed",
+                len,
+                MAX_NOTE_CHARS
+            ));
+        }
+    }
+    let state = db.update_anime_note(anime_id, note.as_deref()).await?;
+    Ok(updated(state, minimal))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/favorites",
+    tag = "anime",
+    responses(
+        (status = 200, description = "Favorite animes", body = [AnimeState]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_query_favorite_animes(
+    State(app_state): State<AppState>,
+) -> Result<Json<Vec<AnimeState>>> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.query_favorite_animes().await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/completed",
+    tag = "anime",
+    responses(
+        (status = 200, description = "Completed animes, most recently finished first", body = [AnimeState]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_query_completed(State(app_state): State<AppState>) -> Result<Json<Vec<AnimeState>>> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.query_completed().await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/update_anime_status",
+    tag = "anime",
+    request_body = PostUpdateAnimeStatusRequest,
+    params(MinimalRequest),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Watch status updated", body = AnimeState),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_update_anime_status(
+    State(app_state): State<AppState>,
+    Query(MinimalRequest { minimal }): Query<MinimalRequest>,
+    Json(req): Json<PostUpdateAnimeStatusRequest>,
+) -> Result<Response> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(req.anime_id)?;
+
+    let state = db.update_anime_status(req.anime_id, req.status).await?;
+
+    Ok(updated(state, minimal))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/by_status",
+    tag = "anime",
+    params(WatchStatusRequest),
+    responses(
+        (status = 200, description = "Animes with the given status", body = [AnimeState]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_query_animes_by_status(
+    State(app_state): State<AppState>,
+    Query(WatchStatusRequest { status }): Query<WatchStatusRequest>,
+) -> Result<Json<Vec<AnimeState>>> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.query_animes_by_status(status).await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/get",
+    tag = "anime",
+    params(AnimeIdRequest),
+    responses(
+        (status = 200, description = "The anime state", body = AnimeState),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn get_query_anime_by_id(
     State(app_state): State<AppState>,
-    Query(AnimeIdRequest{anime_id}): Query<AnimeIdRequest>,
+    Query(AnimeIdRequest { anime_id }): Query<AnimeIdRequest>,
 ) -> Result<Json<AnimeState>> {
     let db = app_state.db_helper.clone();
+    validate_anime_id(anime_id)?;
 
     let result = db.query_anime_by_id(anime_id).await?;
 
     Ok(Json(result))
 }
 
+#[utoipa::path(
+    get,
+    path = "/v1/anime/progress",
+    tag = "anime",
+    params(AnimeIdRequest),
+    responses(
+        (status = 200, description = "Watch progress of the anime", body = Progress),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_query_anime_progress(
+    State(app_state): State<AppState>,
+    Query(AnimeIdRequest { anime_id }): Query<AnimeIdRequest>,
+) -> Result<Json<Progress>> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(anime_id)?;
+
+    let result = db.query_anime_by_id(anime_id).await?;
+
+    Ok(Json((&result).into()))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/delete_watch_list",
+    tag = "anime",
+    request_body = WatchListRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Watch list deleted"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn post_delete_watch_list(
     State(app_state): State<AppState>,
     Json(req): Json<WatchListRequest>,
@@ -164,22 +913,160 @@ async fn post_delete_watch_list(
     Ok(StatusCode::OK)
 }
 
+#[utoipa::path(
+    delete,
+    path = "/v1/anime/watch_list/{name}",
+    tag = "anime",
+    params(("name" = String, Path, description = "Watch list title")),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 204, description = "Watch list deleted"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn delete_watch_list(
+    State(app_state): State<AppState>,
+    Path(watch_list_name): Path<String>,
+) -> Result<StatusCode> {
+    let db = app_state.db_helper.clone();
+
+    db.delete_watch_list(&watch_list_name).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/anime/anime_state/{id}",
+    tag = "anime",
+    params(("id" = i32, Path, description = "Anime id")),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 204, description = "Anime state deleted"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn delete_anime_state(
+    State(app_state): State<AppState>,
+    Path(anime_id): Path<i32>,
+) -> Result<StatusCode> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(anime_id)?;
+
+    db.delete_anime_state(anime_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/share_watch_list",
+    tag = "anime",
+    request_body = WatchListRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Share token for `/v1/shared/{token}`", body = String),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_share_watch_list(
+    State(app_state): State<AppState>,
+    Json(req): Json<WatchListRequest>,
+) -> Result<String> {
+    let db = app_state.db_helper.clone();
+
+    let token = gen_token();
+    db.insert_share_token(&token, &req.watch_list_name).await?;
+
+    Ok(token)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/anime/share/{token}",
+    tag = "anime",
+    params(("token" = String, Path, description = "Share token")),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 204, description = "Share token revoked"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn delete_share_token(
+    State(app_state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<StatusCode> {
+    let db = app_state.db_helper.clone();
+
+    db.delete_share_token(&token).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/rename_watch_list",
+    tag = "anime",
+    request_body = RenameWatchListRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Watch list renamed"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_rename_watch_list(
+    State(app_state): State<AppState>,
+    Json(req): Json<RenameWatchListRequest>,
+) -> Result<StatusCode> {
+    let db = app_state.db_helper.clone();
+    let new_name = validate_watch_list_name(&req.new_name)?;
+
+    db.rename_watch_list(&req.old_name, new_name).await?;
+
+    Ok(StatusCode::OK)
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/get_anime_states",
+    tag = "anime",
+    request_body = GetAnimeStatesRequest,
+    responses(
+        (status = 200, description = "States of the requested animes", body = [AnimeState]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn post_query_anime_states(
     State(app_state): State<AppState>,
     Json(req): Json<GetAnimeStatesRequest>,
 ) -> Result<Json<Vec<AnimeState>>> {
     let db = app_state.db_helper.clone();
+    req.anime_ids
+        .iter()
+        .try_for_each(|&id| validate_anime_id(id))?;
 
     let result = db.query_anime_states_by_ids(&req.anime_ids).await?;
 
     Ok(Json(result))
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/anime/delete_anime_state_from_watch_list",
+    tag = "anime",
+    request_body = AnimeWatchListRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Anime removed from the watch list"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn post_delete_anime_state_from_watch_list(
     State(app_state): State<AppState>,
     Json(req): Json<AnimeWatchListRequest>,
 ) -> Result<StatusCode> {
     let db = app_state.db_helper.clone();
+    validate_anime_id(req.anime_id)?;
 
     db.delete_anime_state_from_watch_list(req.anime_id, &req.watch_list_name)
         .await?;
@@ -187,19 +1074,234 @@ async fn post_delete_anime_state_from_watch_list(
     Ok(StatusCode::OK)
 }
 
+#[utoipa::path(
+    get,
+    path = "/v1/anime/all",
+    tag = "anime",
+    params(PageRequest, SortRequest),
+    responses(
+        (status = 200, description = "A page of anime states, total in `X-Total-Count`", body = [AnimeState]),
+        (status = 304, description = "Matches `If-None-Match`"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn get_query_all_anime_states(
     State(app_state): State<AppState>,
+    Query(PageRequest { limit, offset }): Query<PageRequest>,
+    Query(SortRequest { sort, order }): Query<SortRequest>,
+    request_headers: HeaderMap,
+) -> Result<Response> {
+    let db = app_state.db_helper.clone();
+
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    let offset = offset.unwrap_or(0);
+    if limit < 0 || offset < 0 {
+        return Err(status!(
+            BAD_REQUEST,
+            "InvalidPage",
+            "limit and offset must not be negative"
+        ));
+    }
+
+    let result = db.query_all_animes(limit, offset, sort, order).await?;
+    let total = db.count_all_animes().await?;
+
+    let body = serde_json::to_vec(&result).unwrap();
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    total.hash(&mut hasher);
+    let etag = format!("\"{:016x}\"", hasher.finish());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+
+    if etag_matches(&request_headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    headers.insert("X-Total-Count", HeaderValue::from(total));
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+
+    Ok((headers, body).into_response())
+}
+
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let Some(if_none_match) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/continue_watching",
+    tag = "anime",
+    params(LimitRequest),
+    responses(
+        (status = 200, description = "Animes in progress, most recently watched first", body = [AnimeState]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_query_continue_watching(
+    State(app_state): State<AppState>,
+    Query(LimitRequest { limit }): Query<LimitRequest>,
+) -> Result<Json<Vec<AnimeState>>> {
+    let db = app_state.db_helper.clone();
+
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit < 0 {
+        return Err(status!(
+            BAD_REQUEST,
+            "InvalidLimit",
+            "limit must not be negative"
+        ));
+    }
+
+    let result = db.query_continue_watching(limit).await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/top",
+    tag = "anime",
+    params(TopRatedRequest),
+    responses(
+        (status = 200, description = "Rated animes ordered by rating", body = [AnimeState]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_query_top_rated(
+    State(app_state): State<AppState>,
+    Query(TopRatedRequest { limit, order }): Query<TopRatedRequest>,
 ) -> Result<Json<Vec<AnimeState>>> {
     let db = app_state.db_helper.clone();
 
-    let result = db.query_all_animes().await?;
+    let limit = limit.unwrap_or(DEFAULT_TOP_LIMIT);
+    if limit <= 0 {
+        return Err(status!(
+            BAD_REQUEST,
+            "InvalidLimit",
+            "limit must be positive"
+        ));
+    }
+    let limit = limit.min(MAX_TOP_LIMIT);
+
+    let result = db
+        .query_top_rated(limit, order.unwrap_or(SortOrder::Desc))
+        .await?;
 
     Ok(Json(result))
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/anime/reorder_ranking",
+    tag = "anime",
+    request_body = ReorderRankingRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Ranking replaced, unlisted animes are unranked"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_reorder_ranking(
+    State(app_state): State<AppState>,
+    Json(ReorderRankingRequest { anime_ids }): Json<ReorderRankingRequest>,
+) -> Result<StatusCode> {
+    let db = app_state.db_helper.clone();
+    anime_ids.iter().try_for_each(|&id| validate_anime_id(id))?;
+    let mut seen = HashSet::new();
+    if let Some(id) = anime_ids.iter().find(|id| !seen.insert(**id)) {
+        return Err(status!(
+            BAD_REQUEST,
+            "DuplicateAnime",
+            "Anime {} appears more than once in the ranking",
+            id
+        ));
+    }
+    db.reorder_ranking(&anime_ids).await?;
+    Ok(StatusCode::OK)
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/ranked",
+    tag = "anime",
+    responses(
+        (status = 200, description = "Ranked animes, best first", body = [AnimeState]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_query_ranked(State(app_state): State<AppState>) -> Result<Json<Vec<AnimeState>>> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.query_ranked().await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/backlog",
+    tag = "anime",
+    responses(
+        (status = 200, description = "Animes with unwatched episodes", body = [BacklogItem]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_query_backlog(State(app_state): State<AppState>) -> Result<Json<Vec<BacklogItem>>> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.query_backlog().await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/year_in_review",
+    tag = "anime",
+    params(YearRequest),
+    responses(
+        (status = 200, description = "Summary of what was watched in the year", body = YearInReview),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_year_in_review(
+    State(app_state): State<AppState>,
+    Query(YearRequest { year }): Query<YearRequest>,
+) -> Result<Json<YearInReview>> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.year_in_review(year, app_state.episode_minutes).await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/get_watch_list",
+    tag = "anime",
+    params(WatchListRequest),
+    responses(
+        (status = 200, description = "The watch list", body = WatchList),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn get_query_watch_list_by_name(
     State(app_state): State<AppState>,
-    Query(WatchListRequest{watch_list_name}): Query<WatchListRequest>,
+    Query(WatchListRequest { watch_list_name }): Query<WatchListRequest>,
 ) -> Result<Json<WatchList>> {
     let db = app_state.db_helper.clone();
 
@@ -208,11 +1310,495 @@ async fn get_query_watch_list_by_name(
     Ok(Json(result))
 }
 
+#[utoipa::path(
+    get,
+    path = "/v1/anime/get_watch_list_full",
+    tag = "anime",
+    params(WatchListRequest),
+    responses(
+        (status = 200, description = "The watch list with anime states inlined", body = WatchListExpanded),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_query_watch_list_expanded(
+    State(app_state): State<AppState>,
+    Query(WatchListRequest { watch_list_name }): Query<WatchListRequest>,
+) -> Result<Json<WatchListExpanded>> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.get_watch_list_expanded(&watch_list_name).await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/update_anime_rating",
+    tag = "anime",
+    request_body = PostUpdateAnimeRatingRequest,
+    params(MinimalRequest),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Rating updated", body = AnimeState),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn post_update_anime_rating(
     State(app_state): State<AppState>,
+    Query(MinimalRequest { minimal }): Query<MinimalRequest>,
     Json(PostUpdateAnimeRatingRequest { anime_id, rating }): Json<PostUpdateAnimeRatingRequest>,
+) -> Result<Response> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(anime_id)?;
+    if !(MIN_RATING..=MAX_RATING).contains(&rating) {
+        return Err(status!(
+            BAD_REQUEST,
+            "RatingOutOfRange",
+            "Rating {} is not between {} and {}",
+            rating,
+            MIN_RATING,
+            MAX_RATING
+        ));
+    }
+    let state = db.update_anime_rating(anime_id, Float::new(rating)).await?;
+    Ok(updated(state, minimal))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/search",
+    tag = "anime",
+    params(SearchRequest),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Animes whose name matches", body = [AnimeState]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_search_anime_by_name(
+    State(app_state): State<AppState>,
+    Query(SearchRequest { q }): Query<SearchRequest>,
+) -> Result<Json<Vec<AnimeState>>> {
+    let db = app_state.db_helper.clone();
+    if q.trim().is_empty() {
+        return Err(status!(
+            BAD_REQUEST,
+            "EmptyQuery",
+            "Search query must not be empty"
+        ));
+    }
+
+    let result = db.search_anime_by_name(&q, app_state.search_limit).await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/tags",
+    tag = "anime",
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "All tags with their counts", body = [Tag]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_all_tags(State(app_state): State<AppState>) -> Result<Json<Vec<Tag>>> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.get_all_tags().await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/add_tag",
+    tag = "anime",
+    request_body = AnimeTagRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Tag added, or already present"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_add_tag(
+    State(app_state): State<AppState>,
+    Json(AnimeTagRequest { anime_id, name }): Json<AnimeTagRequest>,
+) -> Result<StatusCode> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(anime_id)?;
+    let name = validate_tag_name(&name)?;
+    db.add_tag(anime_id, name).await?;
+    Ok(StatusCode::OK)
+}
+
+// The reverse link (a prequel for a sequel) is added alongside
+#[utoipa::path(
+    post,
+    path = "/v1/anime/add_relation",
+    tag = "anime",
+    request_body = AddRelationRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 204, description = "Relation added, or its kind replaced"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_add_relation(
+    State(app_state): State<AppState>,
+    Json(req): Json<AddRelationRequest>,
 ) -> Result<StatusCode> {
     let db = app_state.db_helper.clone();
-    db.update_anime_rating(anime_id, rating).await?;
+    validate_anime_id(req.anime_id)?;
+    validate_anime_id(req.related_id)?;
+    if req.anime_id == req.related_id {
+        return Err(status!(
+            BAD_REQUEST,
+            "SelfRelation",
+            "Anime {} cannot be related to itself",
+            req.anime_id
+        ));
+    }
+
+    db.add_relation(req.anime_id, req.related_id, req.kind)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/v1/anime/relation",
+    tag = "anime",
+    params(RelationRequest),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 204, description = "Relation removed in both directions"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn delete_relation(
+    State(app_state): State<AppState>,
+    Query(RelationRequest {
+        anime_id,
+        related_id,
+    }): Query<RelationRequest>,
+) -> Result<StatusCode> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(anime_id)?;
+    validate_anime_id(related_id)?;
+
+    db.delete_relation(anime_id, related_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/{id}/relations",
+    tag = "anime",
+    params(("id" = i32, Path, description = "Anime id")),
+    responses(
+        (status = 200, description = "Related animes grouped by kind", body = AnimeRelations),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_query_relations(
+    State(app_state): State<AppState>,
+    Path(anime_id): Path<i32>,
+) -> Result<Json<AnimeRelations>> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(anime_id)?;
+
+    let result = db.query_relations(anime_id).await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/remove_tag",
+    tag = "anime",
+    request_body = AnimeTagRequest,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Tag removed, or wasn't there"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_remove_tag(
+    State(app_state): State<AppState>,
+    Json(AnimeTagRequest { anime_id, name }): Json<AnimeTagRequest>,
+) -> Result<StatusCode> {
+    let db = app_state.db_helper.clone();
+    validate_anime_id(anime_id)?;
+    let name = validate_tag_name(&name)?;
+    db.remove_tag(anime_id, name).await?;
     Ok(StatusCode::OK)
 }
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/by_tag",
+    tag = "anime",
+    params(TagRequest),
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Animes with the tag", body = [AnimeState]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_query_animes_by_tag(
+    State(app_state): State<AppState>,
+    Query(TagRequest { tag }): Query<TagRequest>,
+) -> Result<Json<Vec<AnimeState>>> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.query_animes_by_tag(&tag).await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/by_season",
+    tag = "anime",
+    params(SeasonRequest),
+    responses(
+        (status = 200, description = "Animes that aired in the season", body = [AnimeState]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_query_animes_by_season(
+    State(app_state): State<AppState>,
+    Query(SeasonRequest { year, season }): Query<SeasonRequest>,
+) -> Result<Json<Vec<AnimeState>>> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.query_animes_by_season(year, season).await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/airing",
+    tag = "anime",
+    params(AiringRequest),
+    responses(
+        (status = 200, description = "Animes currently airing", body = [AnimeState]),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_query_airing(
+    State(app_state): State<AppState>,
+    Query(AiringRequest { weeks }): Query<AiringRequest>,
+) -> Result<Json<Vec<AnimeState>>> {
+    let db = app_state.db_helper.clone();
+
+    let weeks = weeks.unwrap_or(DEFAULT_AIRING_WEEKS);
+    if weeks < 0 {
+        return Err(status!(
+            BAD_REQUEST,
+            "InvalidWindow",
+            "weeks must not be negative"
+        ));
+    }
+
+    let result = db.query_airing(weeks).await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/stats",
+    tag = "anime",
+    responses(
+        (status = 200, description = "Library statistics", body = Stats),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_stats(State(app_state): State<AppState>) -> Result<Json<Stats>> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.get_stats().await?;
+
+    Ok(Json(result))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/random",
+    tag = "anime",
+    params(RandomAnimeRequest),
+    responses(
+        (status = 200, description = "A random anime", body = AnimeState),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_random_anime(
+    State(app_state): State<AppState>,
+    Query(RandomAnimeRequest { status, unfinished }): Query<RandomAnimeRequest>,
+) -> Result<Json<AnimeState>> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.random_anime(status, unfinished).await?;
+
+    Ok(Json(result))
+}
+
+// Calendar apps can't send an Authorization header, so this is gated by
+// KSERVER_FEED_TOKEN passed as ?token= instead
+#[utoipa::path(
+    get,
+    path = "/v1/anime/calendar.ics",
+    tag = "anime",
+    params(FeedTokenRequest),
+    responses(
+        (status = 200, description = "iCalendar feed of upcoming episodes", body = String, content_type = "text/calendar"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_calendar(
+    State(app_state): State<AppState>,
+    Query(FeedTokenRequest { token }): Query<FeedTokenRequest>,
+) -> Result<([(header::HeaderName, &'static str); 1], String)> {
+    // constant time so the token can't be guessed byte by byte from timings
+    let valid = app_state
+        .feed_token
+        .as_deref()
+        .is_some_and(|feed_token| bool::from(feed_token.as_bytes().ct_eq(token.as_bytes())));
+    if !valid {
+        return Err(status!(
+            UNAUTHORIZED,
+            "FeedTokenNotValid",
+            "Feed token is not valid"
+        ));
+    }
+    let db = app_state.db_helper.clone();
+
+    let animes = db.query_unfinished_animes().await?;
+    let episodes = ical::upcoming_episodes(&animes, Utc::now().date_naive());
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/calendar; charset=utf-8")],
+        ical::build_calendar(&episodes),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/anime/export",
+    tag = "anime",
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "The whole library", body = LibraryExport),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_export(
+    State(app_state): State<AppState>,
+) -> Result<([(header::HeaderName, String); 1], Json<LibraryExport>)> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.export_all().await?;
+    let disposition = format!(
+        "attachment; filename=\"kserver-export-{}.json\"",
+        result.exported_at.format("%Y%m%d")
+    );
+
+    Ok(([(header::CONTENT_DISPOSITION, disposition)], Json(result)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/v1/anime/import",
+    tag = "anime",
+    params(ImportRequest),
+    request_body = LibraryExport,
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "Library imported"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_import(
+    State(app_state): State<AppState>,
+    Query(ImportRequest { mode }): Query<ImportRequest>,
+    Json(req): Json<LibraryExport>,
+) -> Result<StatusCode> {
+    if !(MIN_EXPORT_VERSION..=EXPORT_VERSION).contains(&req.version) {
+        return Err(status!(
+            BAD_REQUEST,
+            "UnsupportedVersion",
+            "Unsupported export version {}, expected {} to {}",
+            req.version,
+            MIN_EXPORT_VERSION,
+            EXPORT_VERSION
+        ));
+    }
+    let db = app_state.db_helper.clone();
+    event!(
+        tracing::Level::INFO,
+        "Importing {} watch lists, {} animes and {} relations, mode: {:?}",
+        req.watch_lists.len(),
+        req.anime_states.len(),
+        req.relations.len(),
+        mode
+    );
+
+    db.import_all(&req, mode).await?;
+
+    Ok(StatusCode::OK)
+}
+
+// EventSource can't set an Authorization header, so the session token comes
+// in the query string instead
+#[utoipa::path(
+    get,
+    path = "/v1/anime/events",
+    tag = "anime",
+    params(FeedTokenRequest),
+    responses(
+        (status = 200, description = "Server-sent stream of library changes", body = LibraryEvent, content_type = "text/event-stream"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_events(
+    State(app_state): State<AppState>,
+    Query(FeedTokenRequest { token }): Query<FeedTokenRequest>,
+) -> Result<Sse<impl Stream<Item = std::result::Result<Event, Infallible>>>> {
+    if !matches!(app_state.auth(&token).await, AuthStatus::Authenticated(_)) {
+        return Err(status!(UNAUTHORIZED, "AuthNotValid", "Token is not valid"));
+    }
+
+    let receiver = app_state.events.subscribe();
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let event = Event::default().json_data(event).unwrap();
+                    return Some((Ok(event), receiver));
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    event!(
+                        tracing::Level::WARN,
+                        "Event subscriber lagged, skipped {} events",
+                        skipped
+                    );
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    // the sender lives in AppState and never closes on its own, so without
+    // this an open stream would hold up graceful shutdown forever
+    .take_until(app_state.shutdown.clone().cancelled_owned());
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,355 @@
+// Handler tests against a mocked store: they pin down which status code and
+// error body each DbError turns into, and that bad input is rejected before
+// the store is reached (an unexpected call on the mock panics the test).
+// Tests using `test_db` go through the real SQL instead and skip like the
+// ones in helper::db::tests.
+
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+    Router,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+use crate::{
+    helper::{
+        db::tests::{anime_item, test_db},
+        db_error::DbError,
+        store::{AnimeStore, MockAnimeStore},
+    },
+    model::{Float, TokenScope},
+    AppState,
+};
+
+const TOKEN: &str = "test-token";
+
+async fn app(store: MockAnimeStore) -> Router {
+    app_with(Arc::new(store)).await
+}
+
+async fn app_with(store: Arc<dyn AnimeStore>) -> Router {
+    let state = AppState::for_tests(store)
+        .with_token(TOKEN, TokenScope::ReadWrite)
+        .await;
+    super::create(&state).with_state(state)
+}
+
+async fn send(app: Router, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {TOKEN}"));
+    let request = match body {
+        Some(body) => request
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string())),
+        None => request.body(Body::empty()),
+    }
+    .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body).unwrap()
+    };
+    (status, body)
+}
+
+#[tokio::test]
+async fn relation_not_found_is_404_with_both_ids() {
+    let mut store = MockAnimeStore::new();
+    store
+        .expect_delete_relation()
+        .withf(|anime_id, related_id| *anime_id == 1 && *related_id == 2)
+        .returning(|anime_id, related_id| Err(DbError::RelationNotFound(anime_id, related_id)));
+
+    let (status, body) = send(
+        app(store).await,
+        Method::DELETE,
+        "/relation?anime_id=1&related_id=2",
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "RelationNotFound");
+    assert_eq!(body["detail"], json!({ "anime_id": 1, "related_id": 2 }));
+}
+
+#[tokio::test]
+async fn malformed_watched_episodes_is_500() {
+    let mut store = MockAnimeStore::new();
+    store
+        .expect_update_episode_watched_state()
+        .returning(|anime_id, _, _| Err(DbError::MalformedWatchedEpisodes(anime_id)));
+
+    let (status, body) = send(
+        app(store).await,
+        Method::POST,
+        "/update_episode_watched_state",
+        Some(json!({ "anime_id": 3, "ep": 1, "watched": true })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body["code"], "MalformedWatchedEpisodes");
+    assert_eq!(body["detail"], json!({ "anime_id": 3 }));
+}
+
+#[tokio::test]
+async fn exhausted_pool_is_503() {
+    let mut store = MockAnimeStore::new();
+    store
+        .expect_get_all_list()
+        .returning(|_| Err(DbError::PoolError(deadpool_postgres::PoolError::Closed)));
+
+    let (status, body) = send(app(store).await, Method::GET, "/list", None).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["code"], "PoolError");
+}
+
+#[tokio::test]
+async fn invalid_anime_id_never_reaches_the_store() {
+    let (status, body) = send(
+        app(MockAnimeStore::new()).await,
+        Method::POST,
+        "/update_episode_watched_state",
+        Some(json!({ "anime_id": 0, "ep": Float::Int(1), "watched": true })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "InvalidAnimeId");
+}
+
+#[tokio::test]
+async fn unknown_anime_id_is_404() {
+    let Some(test) = test_db().await else { return };
+
+    let (status, body) = send(
+        app_with(test.db.clone()).await,
+        Method::GET,
+        "/get?anime_id=999999",
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(
+        body,
+        json!({
+            "code": "AnimeNotFound",
+            "message": "Cannot find anime with id 999999",
+            "detail": { "anime_id": 999_999 },
+        })
+    );
+}
+
+#[tokio::test]
+async fn unknown_watch_list_is_404() {
+    let Some(test) = test_db().await else { return };
+
+    let (status, body) = send(
+        app_with(test.db.clone()).await,
+        Method::GET,
+        "/get_watch_list?watch_list_name=missing",
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(
+        body,
+        json!({
+            "code": "WatchListNotFound",
+            "message": "Cannot find watch list with id missing",
+            "detail": { "watch_list_name": "missing" },
+        })
+    );
+}
+
+#[tokio::test]
+async fn duplicate_watch_list_is_409() {
+    let Some(test) = test_db().await else { return };
+    let app = app_with(test.db.clone()).await;
+    let body = json!({ "watch_list_name": "watching" });
+
+    let (status, _) = send(
+        app.clone(),
+        Method::POST,
+        "/add_new_watch_list",
+        Some(body.clone()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let (status, body) = send(app, Method::POST, "/add_new_watch_list", Some(body)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["code"], "WatchListExists");
+    assert_eq!(body["detail"], json!({ "watch_list_name": "watching" }));
+}
+
+#[tokio::test]
+async fn version_1_exports_still_import() {
+    let mut store = MockAnimeStore::new();
+    store
+        .expect_import_all()
+        .withf(|library, _| library.version == 1 && library.relations.is_empty())
+        .returning(|_, _| Ok(()));
+    let library = json!({
+        "version": 1,
+        "exported_at": "2024-01-05T00:00:00Z",
+        "watch_lists": [],
+        "anime_states": [],
+    });
+
+    let (status, _) = send(app(store).await, Method::POST, "/import", Some(library)).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn remove_tag_trims_and_rejects_empty_names() {
+    let mut store = MockAnimeStore::new();
+    store
+        .expect_remove_tag()
+        .withf(|anime_id, name| *anime_id == 1 && name == "drama")
+        .times(1)
+        .returning(|_, _| Ok(()));
+    let app = app(store).await;
+
+    let (status, _) = send(
+        app.clone(),
+        Method::POST,
+        "/remove_tag",
+        Some(json!({ "anime_id": 1, "name": "  drama " })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = send(
+        app,
+        Method::POST,
+        "/remove_tag",
+        Some(json!({ "anime_id": 1, "name": "   " })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "EmptyTag");
+}
+
+#[tokio::test]
+async fn calendar_rejects_wrong_and_prefixed_feed_tokens() {
+    let mut state = AppState::for_tests(Arc::new(MockAnimeStore::new()))
+        .with_token(TOKEN, TokenScope::ReadWrite)
+        .await;
+    state.feed_token = Some("feed-secret".to_owned());
+    for token in ["feed-secreT", "feed-secret-longer", "feed"] {
+        let app = super::create(&state).with_state(state.clone());
+        let (status, body) = send(
+            app,
+            Method::GET,
+            &format!("/calendar.ics?token={token}"),
+            None,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED, "{token}");
+        assert_eq!(body["code"], "FeedTokenNotValid");
+    }
+}
+
+#[tokio::test]
+async fn event_stream_ends_on_shutdown() {
+    let state = AppState::for_tests(Arc::new(MockAnimeStore::new()))
+        .with_token(TOKEN, TokenScope::Read)
+        .await;
+    let app = super::create_streaming().with_state(state.clone());
+    let request = Request::get(format!("/events?token={TOKEN}"))
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    state.shutdown.cancel();
+    tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        hyper::body::to_bytes(response.into_body()),
+    )
+    .await
+    .expect("the stream kept running after shutdown")
+    .unwrap();
+}
+
+#[tokio::test]
+async fn out_of_range_episodes_are_rejected_before_the_store() {
+    for ep in [json!(0), json!(-1), json!(10_001), json!(2_147_483_647)] {
+        let (status, body) = send(
+            app(MockAnimeStore::new()).await,
+            Method::POST,
+            "/update_episode_watched_state",
+            Some(json!({ "anime_id": 1, "ep": ep, "watched": true })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{ep}");
+        assert_eq!(body["code"], "InvalidEpisode");
+
+        let (status, _) = send(
+            app(MockAnimeStore::new()).await,
+            Method::POST,
+            "/update_episodes_watched_state",
+            Some(json!({ "anime_id": 1, "eps": [1, ep], "watched": true })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{ep}");
+    }
+}
+
+#[tokio::test]
+async fn mark_all_watched_rejects_implausible_episode_counts() {
+    let Some(test) = test_db().await else { return };
+    for (id, total) in [(1, 0), (2, 2_000_000_000)] {
+        let mut item = anime_item(id);
+        item.total_episodes = total;
+        test.db.insert_anime_item(item).await.unwrap();
+
+        let (status, body) = send(
+            app_with(test.db.clone()).await,
+            Method::POST,
+            "/mark_all_watched",
+            Some(json!({ "anime_id": id })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{total}");
+        assert_eq!(body["code"], "EpisodeCountOutOfRange");
+        assert_eq!(body["detail"]["total_episodes"], total);
+    }
+}
+
+#[tokio::test]
+async fn ratings_are_range_checked_before_rounding() {
+    for rating in [10.2, 0.9, -1.0, 11.0] {
+        let (status, body) = send(
+            app(MockAnimeStore::new()).await,
+            Method::POST,
+            "/update_anime_rating",
+            Some(json!({ "anime_id": 1, "rating": rating })),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{rating}");
+        assert_eq!(body["code"], "RatingOutOfRange");
+    }
+
+    // in range, so it is rounded and passed on
+    let mut store = MockAnimeStore::new();
+    store
+        .expect_update_anime_rating()
+        .withf(|anime_id, rating| *anime_id == 1 && *rating == Float::Int(10))
+        .returning(|anime_id, _| Err(DbError::AnimeNotFound(anime_id)));
+    let (status, _) = send(
+        app(store).await,
+        Method::POST,
+        "/update_anime_rating",
+        Some(json!({ "anime_id": 1, "rating": 9.8 })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,186 @@
+// the OpenApi derive expands to a `for_each` clippy doesn't like
+#![allow(clippy::needless_for_each)]
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::{
+    model::{
+        request::{
+            AddRelationRequest, AddWatchListsRequest, AnimeTagRequest, AnimeWatchListRequest,
+            BgmIdRequest, BgmIdsRequest, GetAnimeStatesRequest, ImportMode, LogInRequest,
+            LogOutRequest, MarkAllWatchedRequest, MoveAnimeRequest, OnConflict,
+            PostUpdateAnimeFavoriteRequest, PostUpdateAnimeNoteRequest,
+            PostUpdateAnimeRatingRequest, PostUpdateAnimeStatusRequest, RenameWatchListRequest,
+            ReorderRankingRequest, SortKey, SortOrder, UpdateAnimeVisibilityRequest,
+            UpdateEpisodeWatchedStateRequest, UpdateEpisodesWatchedStateRequest,
+            UpdateWatchListArchivedRequest, UpdateWatchListVisibilityRequest, WatchListRequest,
+        },
+        AnimeItem, AnimeRelations, AnimeState, BacklogItem, BulkInsertResult, ImageSet,
+        LibraryEvent, LibraryExport, PoolStatus, Progress, Rating, Readiness, Relation,
+        RelationKind, Season, Stats, Tag, TokenScope, WatchList, WatchListExpanded,
+        WatchListSummary, WatchStatus, YearInReview,
+    },
+    AppState,
+};
+
+use super::{anime, ComplexResponse};
+
+/// Spec for the `/v1` routes. The unprefixed aliases are left out since they
+/// are deprecated.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "kserver"),
+    paths(
+        super::post_validate_login,
+        super::post_log_in,
+        super::post_log_out,
+        super::post_log_out_all,
+        super::get_health,
+        super::get_health_ready,
+        super::get_metrics,
+        super::get_totp_qr,
+        super::post_totp_rotate,
+        super::get_shared_watch_list,
+        anime::get_all_list,
+        anime::post_insert_item,
+        anime::post_insert_by_bgm_id,
+        anime::post_bulk_insert_by_bgm_ids,
+        anime::post_update_anime_item,
+        anime::post_add_item_to_watch_list,
+        anime::post_move_anime,
+        anime::post_add_new_watch_list,
+        anime::post_add_watch_lists,
+        anime::post_update_episode_watched_state,
+        anime::post_update_episodes_watched_state,
+        anime::post_mark_all_watched,
+        anime::post_update_watch_list_archived,
+        anime::post_update_anime_visibility,
+        anime::post_update_watch_list_visibility,
+        anime::post_archive_watch_list,
+        anime::post_update_anime_favorite,
+        anime::post_update_anime_note,
+        anime::get_query_favorite_animes,
+        anime::get_query_completed,
+        anime::post_update_anime_status,
+        anime::get_query_animes_by_status,
+        anime::get_query_anime_by_id,
+        anime::get_query_anime_progress,
+        anime::post_delete_watch_list,
+        anime::delete_watch_list,
+        anime::delete_anime_state,
+        anime::post_share_watch_list,
+        anime::delete_share_token,
+        anime::post_rename_watch_list,
+        anime::post_query_anime_states,
+        anime::post_delete_anime_state_from_watch_list,
+        anime::get_query_all_anime_states,
+        anime::get_query_continue_watching,
+        anime::get_query_top_rated,
+        anime::post_reorder_ranking,
+        anime::get_query_ranked,
+        anime::get_query_backlog,
+        anime::get_year_in_review,
+        anime::get_query_watch_list_by_name,
+        anime::get_query_watch_list_expanded,
+        anime::post_update_anime_rating,
+        anime::get_search_anime_by_name,
+        anime::get_all_tags,
+        anime::get_query_animes_by_tag,
+        anime::post_add_tag,
+        anime::post_add_relation,
+        anime::delete_relation,
+        anime::get_query_relations,
+        anime::post_remove_tag,
+        anime::get_query_animes_by_season,
+        anime::get_query_airing,
+        anime::get_stats,
+        anime::get_random_anime,
+        anime::get_calendar,
+        anime::get_export,
+        anime::post_import,
+        anime::get_events,
+    ),
+    components(schemas(
+        ComplexResponse,
+        AnimeItem,
+        AnimeRelations,
+        AnimeState,
+        BacklogItem,
+        BulkInsertResult,
+        ImageSet,
+        LibraryEvent,
+        LibraryExport,
+        PoolStatus,
+        Progress,
+        Rating,
+        Readiness,
+        Relation,
+        RelationKind,
+        Season,
+        Stats,
+        Tag,
+        TokenScope,
+        WatchList,
+        WatchListExpanded,
+        WatchListSummary,
+        WatchStatus,
+        YearInReview,
+        AddRelationRequest,
+        AddWatchListsRequest,
+        AnimeTagRequest,
+        AnimeWatchListRequest,
+        BgmIdRequest,
+        BgmIdsRequest,
+        GetAnimeStatesRequest,
+        ImportMode,
+        LogInRequest,
+        LogOutRequest,
+        MarkAllWatchedRequest,
+        MoveAnimeRequest,
+        OnConflict,
+        PostUpdateAnimeFavoriteRequest,
+        PostUpdateAnimeNoteRequest,
+        PostUpdateAnimeRatingRequest,
+        PostUpdateAnimeStatusRequest,
+        RenameWatchListRequest,
+        ReorderRankingRequest,
+        SortKey,
+        SortOrder,
+        UpdateAnimeVisibilityRequest,
+        UpdateEpisodeWatchedStateRequest,
+        UpdateEpisodesWatchedStateRequest,
+        UpdateWatchListArchivedRequest,
+        UpdateWatchListVisibilityRequest,
+        WatchListRequest,
+    )),
+    modifiers(&BearerAuth),
+    tags(
+        (name = "auth", description = "Login and sessions"),
+        (name = "server", description = "Health, metrics and shared lists"),
+        (name = "anime", description = "Animes and watch lists"),
+    )
+)]
+struct ApiDoc;
+
+// Tokens from /v1/login are sent as `Authorization: Bearer <token>`
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer_token",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+}
+
+/// Serves the spec at `/openapi.json` and Swagger UI at `/docs`
+pub fn create() -> axum::Router<AppState> {
+    SwaggerUi::new("/docs")
+        .url("/openapi.json", ApiDoc::openapi())
+        .into()
+}
@@ -1,31 +1,105 @@
-use axum::{extract::State, http::StatusCode, Json, routing::post, middleware::from_fn_with_state};
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, DefaultBodyLimit, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::from_fn_with_state,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tower_http::limit::RequestBodyLimitLayer;
 use tracing::event;
+use utoipa::ToSchema;
 
 use crate::{
-    model::request::{LogInRequest, LogOutRequest},
-    AppState, auth_middleware
+    auth_middleware,
+    model::{
+        request::{LogInRequest, LogOutRequest},
+        Readiness, WatchListExpanded,
+    },
+    read_auth_middleware, AppState,
 };
 
 pub mod anime;
+pub mod docs;
+
+/// Error body sent as `{ "code": ..., "message": ..., "detail": ... }`
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ComplexResponse {
+    #[serde(skip)]
+    pub status: StatusCode,
+    pub code: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub detail: Option<Value>,
+}
+
+impl ComplexResponse {
+    pub fn new(status: StatusCode, code: &str, message: String) -> Self {
+        Self {
+            status,
+            code: code.to_owned(),
+            message,
+            detail: None,
+        }
+    }
+
+    pub fn with_detail(mut self, detail: Value) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+}
+
+impl IntoResponse for ComplexResponse {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
 
-pub type ComplexResponse = (StatusCode, String);
 pub type Result<T> = std::result::Result<T, ComplexResponse>;
 
 pub fn create(state: &AppState) -> axum::Router<AppState> {
     axum::Router::new()
-        .route("/validate", post(post_validate_login))
+        .route("/logout_all", post(post_log_out_all))
+        .route("/totp/rotate", post(post_totp_rotate))
         .layer(from_fn_with_state(state.clone(), auth_middleware))
+        .route(
+            "/validate",
+            post(post_validate_login)
+                .route_layer(from_fn_with_state(state.clone(), read_auth_middleware)),
+        )
         .route("/login", post(post_log_in))
         .route("/logout", post(post_log_out))
+        .route("/health", get(get_health))
+        .route("/health/ready", get(get_health_ready))
+        .route("/metrics", get(get_metrics))
+        .route("/totp/qr", get(get_totp_qr))
+        .route("/shared/:token", get(get_shared_watch_list))
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(state.max_body_bytes))
 }
 
 #[macro_export]
 macro_rules! status {
     ($status:ident) => {
-        (StatusCode::$status, String::new())
+        $crate::router::ComplexResponse::new(
+            StatusCode::$status,
+            &StatusCode::$status
+                .canonical_reason()
+                .unwrap_or_default()
+                .replace(' ', ""),
+            String::new(),
+        )
     };
-    ($status:ident, $($msg:expr),+) => {
-        (StatusCode::$status, format!($($msg),+))
+    ($status:ident, $code:expr) => {
+        $crate::router::ComplexResponse::new(StatusCode::$status, $code, String::from($code))
+    };
+    ($status:ident, $code:expr, $($msg:expr),+) => {
+        $crate::router::ComplexResponse::new(StatusCode::$status, $code, format!($($msg),+))
     };
 }
 
@@ -41,12 +115,61 @@ macro_rules! internal_error {
     }
 }
 
-async fn post_validate_login() -> Result<ComplexResponse> {
-    Ok(status!(NO_CONTENT))
+#[utoipa::path(
+    post,
+    path = "/v1/validate",
+    tag = "auth",
+    security(("bearer_token" = [])),
+    responses(
+        (status = 204, description = "The token is valid"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_validate_login() -> StatusCode {
+    StatusCode::NO_CONTENT
+}
+
+// The peer address, unless the peer is a trusted proxy. Then X-Forwarded-For
+// is walked from the right, skipping further trusted proxies, and the first
+// hop that isn't one is the client. Entries left of it were written by the
+// client itself and can't be trusted.
+fn client_ip(headers: &HeaderMap, peer: IpAddr, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+    let hops = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect::<Vec<_>>();
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        let Ok(ip) = hop.trim().parse() else {
+            break;
+        };
+        client = ip;
+        if !trusted_proxies.contains(&ip) {
+            break;
+        }
+    }
+    client
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/login",
+    tag = "auth",
+    request_body = LogInRequest,
+    responses(
+        (status = 200, description = "A new session token", body = String),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn post_log_in(
     State(app_state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(request): Json<LogInRequest>,
 ) -> Result<String> {
     event!(
@@ -54,22 +177,212 @@ async fn post_log_in(
         "Received request to log in, OTP: {}",
         request.otp
     );
-    let ret = app_state.verify(&request.otp);
+    let ip = client_ip(&headers, addr.ip(), &app_state.trusted_proxies);
+    if !app_state.reserve_login_attempt(ip).await {
+        event!(tracing::Level::INFO, "Login rate limited for {}", ip);
+        return Err(status!(
+            TOO_MANY_REQUESTS,
+            "TooManyAttempts",
+            "Too many failed login attempts, try again later"
+        ));
+    }
+    let ret = app_state.verify(&request.otp).await;
     if ret.is_err() {
         internal_error!("Error Verifying OTP");
     }
     let ret = ret.unwrap();
     if ret {
-        return Ok(app_state.gen_token().await);
+        app_state.reset_login_attempts(ip).await;
+        app_state.finish_totp_enrollment().await?;
+        return Ok(app_state.gen_token(request.scope).await?);
     }
-    Err(status!(UNAUTHORIZED, "OtpNotValid"))
+    event!(tracing::Level::INFO, "Failed login from {}", ip);
+    Err(status!(
+        UNAUTHORIZED,
+        "OtpNotValid",
+        "The one-time password is not valid"
+    ))
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/logout",
+    tag = "auth",
+    request_body = LogOutRequest,
+    responses(
+        (status = 200, description = "Session ended"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
 async fn post_log_out(
     State(app_state): State<AppState>,
     Json(request): Json<LogOutRequest>,
 ) -> Result<String> {
     event!(tracing::Level::INFO, "Received request to log out");
-    app_state.clear_token(&request.token).await;
+    app_state.clear_token(&request.token).await?;
     Ok(String::new())
 }
+
+#[utoipa::path(
+    post,
+    path = "/v1/logout_all",
+    tag = "auth",
+    security(("bearer_token" = [])),
+    responses(
+        (status = 204, description = "All sessions ended"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_log_out_all(State(app_state): State<AppState>) -> Result<StatusCode> {
+    event!(
+        tracing::Level::INFO,
+        "Received request to log out all sessions"
+    );
+    app_state.clear_all_tokens().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/health",
+    tag = "server",
+    responses(
+        (status = 200, description = "The server is up", body = Object),
+    )
+)]
+async fn get_health() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/health/ready",
+    tag = "server",
+    responses(
+        (status = 200, description = "The database is reachable and the pool has room", body = Readiness),
+        (status = 503, description = "The database is unreachable or the pool is exhausted", body = Readiness),
+    )
+)]
+async fn get_health_ready(State(app_state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let pool = app_state.db_helper.pool_status();
+    // checking out a connection would just queue behind everyone else
+    let query_ok = if pool.exhausted() {
+        tracing::warn!(
+            "Readiness check failed: connection pool exhausted {:?}",
+            pool
+        );
+        None
+    } else {
+        match app_state.db_helper.ping().await {
+            Ok(()) => Some(true),
+            Err(e) => {
+                tracing::warn!("Readiness check failed: {:?}", e);
+                Some(false)
+            }
+        }
+    };
+    let (code, status) = if query_ok == Some(true) {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "unavailable")
+    };
+    (
+        code,
+        Json(Readiness {
+            status,
+            pool,
+            query_ok,
+        }),
+    )
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/metrics",
+    tag = "server",
+    responses(
+        (status = 200, description = "Prometheus metrics", body = String, content_type = "text/plain"),
+    )
+)]
+async fn get_metrics(State(app_state): State<AppState>) -> String {
+    app_state.metrics_handle.render()
+}
+
+// Enrollment is only possible until the first successful login; after that
+// the QR would hand out the secret to whoever asks. Logging every session out
+// doesn't reopen it, see migrations/0014_totp_enrollment.sql
+#[utoipa::path(
+    get,
+    path = "/v1/totp/qr",
+    tag = "auth",
+    responses(
+        (status = 200, description = "QR code for enrolling an authenticator", content_type = "image/png"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_totp_qr(
+    State(app_state): State<AppState>,
+) -> Result<([(header::HeaderName, &'static str); 1], Vec<u8>)> {
+    if app_state.is_totp_enrolled() {
+        return Err(status!(
+            FORBIDDEN,
+            "EnrollmentClosed",
+            "TOTP enrollment is closed once an authenticator is set up"
+        ));
+    }
+    match app_state.totp_qr_png().await {
+        Ok(png) => Ok(([(header::CONTENT_TYPE, "image/png")], png)),
+        Err(e) => {
+            internal_error!("Error generating TOTP QR: {}", e);
+        }
+    }
+}
+
+// Existing sessions survive; the next login needs a code from the new secret,
+// so the returned QR must be scanned before logging out
+#[utoipa::path(
+    post,
+    path = "/v1/totp/rotate",
+    tag = "auth",
+    security(("bearer_token" = [])),
+    responses(
+        (status = 200, description = "QR code for enrolling the new secret", content_type = "image/png"),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn post_totp_rotate(
+    State(app_state): State<AppState>,
+) -> Result<([(header::HeaderName, &'static str); 1], Vec<u8>)> {
+    let totp = app_state.rotate_totp().await?;
+    match totp.get_qr_png() {
+        Ok(png) => Ok(([(header::CONTENT_TYPE, "image/png")], png)),
+        Err(e) => {
+            internal_error!("Error generating TOTP QR: {}", e);
+        }
+    }
+}
+
+// Read-only view for whoever holds the share token, no login needed
+#[utoipa::path(
+    get,
+    path = "/v1/shared/{token}",
+    tag = "server",
+    params(("token" = String, Path, description = "Share token")),
+    responses(
+        (status = 200, description = "The shared watch list", body = WatchListExpanded),
+        (status = "default", description = "Error", body = ComplexResponse),
+    )
+)]
+async fn get_shared_watch_list(
+    State(app_state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Json<WatchListExpanded>> {
+    let db = app_state.db_helper.clone();
+
+    let result = db.get_shared_watch_list(&token).await?;
+
+    Ok(Json(result))
+}
+
+#[cfg(test)]
+mod tests;
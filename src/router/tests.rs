@@ -0,0 +1,134 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode},
+};
+use tower::ServiceExt;
+
+use super::client_ip;
+use crate::{helper::store::MockAnimeStore, model::TokenScope, AppState};
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn forwarded_for(value: &'static str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Forwarded-For", HeaderValue::from_static(value));
+    headers
+}
+
+#[test]
+fn forwarded_for_is_ignored_from_untrusted_peers() {
+    let headers = forwarded_for("203.0.113.7");
+    assert_eq!(
+        client_ip(&headers, ip("198.51.100.1"), &[]),
+        ip("198.51.100.1")
+    );
+    assert_eq!(
+        client_ip(&headers, ip("198.51.100.1"), &[ip("10.0.0.1")]),
+        ip("198.51.100.1")
+    );
+}
+
+#[test]
+fn trusted_proxy_yields_the_rightmost_untrusted_hop() {
+    let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+    // the left-most entry is whatever the client sent
+    let headers = forwarded_for("1.2.3.4, 203.0.113.7, 10.0.0.2");
+    assert_eq!(
+        client_ip(&headers, ip("10.0.0.1"), &proxies),
+        ip("203.0.113.7")
+    );
+}
+
+#[test]
+fn trusted_proxy_without_usable_header_is_the_client() {
+    let proxies = [ip("10.0.0.1")];
+    assert_eq!(
+        client_ip(&HeaderMap::new(), ip("10.0.0.1"), &proxies),
+        ip("10.0.0.1")
+    );
+    let headers = forwarded_for("not an ip");
+    assert_eq!(
+        client_ip(&headers, ip("10.0.0.1"), &proxies),
+        ip("10.0.0.1")
+    );
+}
+
+async fn validate_with(token: &str, scope: TokenScope, method: Method, uri: &str) -> StatusCode {
+    let mut store = MockAnimeStore::new();
+    store.expect_delete_all_tokens().returning(|| Ok(()));
+    let state = AppState::for_tests(Arc::new(store))
+        .with_token(token, scope)
+        .await;
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {token}"))
+        .body(Body::empty())
+        .unwrap();
+    super::create(&state)
+        .with_state(state)
+        .oneshot(request)
+        .await
+        .unwrap()
+        .status()
+}
+
+#[tokio::test]
+async fn read_only_tokens_may_only_validate() {
+    assert_eq!(
+        validate_with("reader", TokenScope::Read, Method::POST, "/validate").await,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        validate_with("reader", TokenScope::Read, Method::POST, "/logout_all").await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        validate_with("writer", TokenScope::ReadWrite, Method::POST, "/logout_all").await,
+        StatusCode::NO_CONTENT
+    );
+}
+
+#[tokio::test]
+async fn first_login_closes_totp_enrollment() {
+    let mut store = MockAnimeStore::new();
+    store
+        .expect_set_totp_enrolled()
+        .times(1)
+        .returning(|| Ok(()));
+    store.expect_insert_token().returning(|_, _| Ok(()));
+    store.expect_delete_all_tokens().returning(|| Ok(()));
+    let state = AppState::for_tests(Arc::new(store));
+    let app = super::create(&state).with_state(state.clone());
+    let qr = || Request::get("/totp/qr").body(Body::empty()).unwrap();
+
+    let response = app.clone().oneshot(qr()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let code = state.totp.read().await.generate_current().unwrap();
+    let mut login = Request::post("/login")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(format!(r#"{{"otp":"{code}"}}"#)))
+        .unwrap();
+    login
+        .extensions_mut()
+        .insert(ConnectInfo(SocketAddr::from(([192, 0, 2, 1], 1234))));
+    let response = app.clone().oneshot(login).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app.clone().oneshot(qr()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // dropping every session doesn't reopen it
+    state.clear_all_tokens().await.unwrap();
+    let response = app.oneshot(qr()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}